@@ -0,0 +1,24 @@
+main:
+    cal &'io::print_str
+    cal &'mem::print_str
+    hlt
+.namespace io
+.global print_str
+print_str:
+.arg: 0
+    ptn &1
+    ret
+.end
+.namespace mem
+.global print_str
+print_str:
+.arg: 0
+    ptn &2
+    ret
+.end
+foo:
+    cpy &5 'io::print_str.arg
+    cpy &6 'mem::print_str.arg
+    cpy &7 'print_str.arg
+__tmp: 0
+sp: 255
@@ -1,4 +1,25 @@
-use pest::Span;
+use crate::parser::{ Error, Errors };
+
+/// A piece of one of the files of the program, which knows the file it is in.
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct Span<'a> {
+    inner: pest::Span<'a>,
+    // The index of the file in `Sources`.
+    pub file: usize,
+}
+
+impl<'a> Span<'a> {
+    pub fn new(inner: pest::Span<'a>, file: usize) -> Span<'a> {
+        Span { inner, file }
+    }
+
+    pub fn inner(&self) -> pest::Span<'a> { self.inner.clone() }
+    pub fn start(&self) -> usize { self.inner.start() }
+    pub fn end(&self) -> usize { self.inner.end() }
+    pub fn start_pos(&self) -> pest::Position<'a> { self.inner.start_pos() }
+    pub fn end_pos(&self) -> pest::Position<'a> { self.inner.end_pos() }
+    pub fn as_str(&self) -> &'a str { self.inner.as_str() }
+}
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct Spanned<'a, T> {
@@ -60,6 +81,44 @@ impl Op {
             Op::Ret => 0,
         }
     }
+
+    pub fn from_name(name: &str) -> Option<Op> {
+        match name {
+            "hlt" => Some(Op::Hlt),
+            "add" => Some(Op::Add),
+            "mul" => Some(Op::Mul),
+            "cle" => Some(Op::Cle),
+            "ceq" => Some(Op::Ceq),
+            "jmp" => Some(Op::Jmp),
+            "beq" => Some(Op::Beq),
+            "cpy" => Some(Op::Cpy),
+            "put" => Some(Op::Put),
+            "ptn" => Some(Op::Ptn),
+
+            "psh" => Some(Op::Psh),
+            "pop" => Some(Op::Pop),
+            "cal" => Some(Op::Cal),
+            "ret" => Some(Op::Ret),
+            _     => None,
+        }
+    }
+
+    /// Decodes an opcode as it is stored in the tape. Pseudo instructions have no opcode.
+    pub fn from_opcode(code: i32) -> Option<Op> {
+        match code {
+            0 => Some(Op::Hlt),
+            1 => Some(Op::Add),
+            2 => Some(Op::Mul),
+            3 => Some(Op::Cle),
+            4 => Some(Op::Ceq),
+            5 => Some(Op::Jmp),
+            6 => Some(Op::Beq),
+            7 => Some(Op::Cpy),
+            8 => Some(Op::Put),
+            9 => Some(Op::Ptn),
+            _ => None,
+        }
+    }
 }
 
 pub struct Prog<'a> {
@@ -72,7 +131,20 @@ pub enum Stmt<'a> {
     Label(Label<'a>),
     Inst(Inst<'a>),
     Lit(Lit<'a>),
-    Org(Num<'a>),
+    Org(Lit<'a>),
+    Macro(Macro<'a>),
+    Call(Call<'a>),
+    // Replaced by the statements of the included file when assembling.
+    Include(Include<'a>),
+    Equ(Equ<'a>),
+    Fill(Fill<'a>),
+    Word(Vec<Lit<'a>>),
+    Incbin(Incbin<'a>),
+    Cond(Cond<'a>, Span<'a>),
+    Rept(Rept<'a>),
+    // `.namespace name`, or `.end` when there is no name.
+    Namespace(Option<Str<'a>>, Span<'a>),
+    Global(Vec<Str<'a>>),
 }
 
 pub type Label<'a> = Spanned<'a, (&'a str, usize)>;
@@ -87,6 +159,112 @@ pub struct Inst<'a> {
     pub span: Span<'a>,
 }
 
+#[derive(Debug, Clone)]
+pub struct Macro<'a> {
+    pub name: Str<'a>,
+    pub params: Vec<Str<'a>>,
+    pub body: Vec<Stmt<'a>>,
+    pub span: Span<'a>,
+}
+
+/// A block that is repeated when assembling. Like in macros, local labels defined in the body are
+/// different in each repetition.
+#[derive(Debug, Clone)]
+pub struct Rept<'a> {
+    pub kind: ReptKind<'a>,
+    pub body: Vec<Stmt<'a>>,
+    pub span: Span<'a>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ReptKind<'a> {
+    // `.rept count`.
+    Count(Lit<'a>),
+    // `.irp param, values...`.
+    Irp(Str<'a>, Vec<Lit<'a>>),
+    // `.irpc param, "chars"`.
+    Irpc(Str<'a>, Str<'a>),
+}
+
+/// A use of a macro, which looks just like an instruction.
+#[derive(Debug, Clone)]
+pub struct Call<'a> {
+    pub name: &'a str,
+    pub args: Vec<Arg<'a>>,
+    pub span: Span<'a>,
+}
+
+/// A constant, which is replaced by its value wherever its name is used.
+#[derive(Debug, Clone)]
+pub struct Equ<'a> {
+    pub name: Str<'a>,
+    pub value: Lit<'a>,
+}
+
+/// A value repeated `count` times, written `.fill count value` or `.zero count`.
+#[derive(Debug, Clone)]
+pub struct Fill<'a> {
+    pub count: Lit<'a>,
+    // `.zero` has no value.
+    pub value: Option<Lit<'a>>,
+    pub span: Span<'a>,
+}
+
+/// A directive of conditional assembly, which only starts or ends a block. The statements of the
+/// block are the ones that follow it in the same list.
+#[derive(Debug, Clone)]
+pub enum Cond<'a> {
+    If(Lit<'a>),
+    // `.ifdef`, or `.ifndef` when negated.
+    IfDef { name: Str<'a>, negated: bool },
+    Elif(Lit<'a>),
+    Else,
+    Endif,
+}
+
+/// The contents of a file, embedded in the tape.
+#[derive(Debug, Clone)]
+pub struct Incbin<'a> {
+    pub path: Str<'a>,
+    pub format: IncbinFormat,
+    pub offset: Option<Lit<'a>>,
+    pub length: Option<Lit<'a>>,
+    // The contents of the file once it is loaded, or why it could not be read. Only the files embedded
+    // at the top level of a file are loaded, never the ones inside of macros or repetitions.
+    pub data: Option<std::result::Result<Vec<u8>, Error>>,
+    pub span: Span<'a>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IncbinFormat {
+    // One byte per cell.
+    Bytes,
+    // One number per line, just like the tapes written by the compiler.
+    Tape,
+    // UTF-8 text, one code point per cell.
+    Chars,
+}
+
+#[derive(Debug, Clone)]
+pub struct Include<'a> {
+    pub path: Str<'a>,
+    // Written as `<std/io>`, which names a library bundled with the compiler.
+    pub library: bool,
+    // The included file once it is loaded. Only the files included at the top level of a file are
+    // loaded, never the ones inside of macros or repetitions.
+    pub file: Option<Included<'a>>,
+}
+
+/// What an `.include` brings in. Errors are only reported if the `.include` is assembled, so that
+/// it can be in a branch of an `.if` that is not taken.
+#[derive(Debug, Clone)]
+pub enum Included<'a> {
+    // The index of the file in `Sources` and its statements, or the errors found when parsing it.
+    File(usize, std::result::Result<Vec<Stmt<'a>>, Errors>),
+    // Why the file could not be loaded.
+    Failed(Error),
+}
+
 #[derive(Debug, Clone)]
 pub enum Lit<'a> {
     Lbl(Label<'a>),
@@ -97,6 +275,36 @@ pub enum Lit<'a> {
     // Actually not every lit can be inside `Deref`, only `Lbl`, `Deref` or `Ref`.
     // This is ensured in parsing though.
     Deref(Box<Lit<'a>>),
+    // A macro parameter or a constant.
+    Name(Str<'a>),
+    Expr(Box<Expr<'a>>),
+    // The position of the statement it is in, written `$`.
+    Here(Span<'a>),
+    // How many cells a string or the data after a label takes, written `sizeof(...)`.
+    SizeOf(Box<Lit<'a>>, Span<'a>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// An arithmetic expression, which is evaluated once the position of every label is known.
+#[derive(Debug, Clone)]
+pub struct Expr<'a> {
+    pub op: BinOp,
+    pub lhs: Lit<'a>,
+    pub rhs: Lit<'a>,
+    pub span: Span<'a>,
 }
 
 impl<'a> Lit<'a> {
@@ -108,6 +316,9 @@ impl<'a> Lit<'a> {
             Lit::Chr(c)   => c.span.clone(),
             Lit::Ref(r)   => r.span(),
             Lit::Deref(d) => d.span(),
+            Lit::Name(n)  => n.span.clone(),
+            Lit::Expr(e)  => e.span.clone(),
+            Lit::Here(span) | Lit::SizeOf(_, span) => span.clone(),
         }
     }
 }
@@ -199,7 +410,74 @@ impl<'a> Display for Stmt<'a> {
             Stmt::Label(lbl) => write!(f, "{}:", lbl.inner.0),
             Stmt::Inst(inst) => Display::fmt(inst, f),
             Stmt::Lit(lit)   => Display::fmt(lit, f),
-            Stmt::Org(num)   => write!(f, ".org {}", num.inner),
+            Stmt::Org(lit)   => write!(f, ".org {}", lit),
+            Stmt::Equ(equ)   => write!(f, ".equ {} {}", equ.name, equ.value),
+            Stmt::Fill(fill) => match &fill.value {
+                Some(value) => write!(f, ".fill {} {}", fill.count, value),
+                None        => write!(f, ".zero {}", fill.count),
+            },
+            Stmt::Word(lits) => {
+                let lits: Vec<_> = lits.iter().map(Lit::to_string).collect();
+                write!(f, ".word {}", lits.join(", "))
+            },
+            Stmt::Macro(mac) => {
+                write!(f, ".macro {}", mac.name)?;
+                for param in &mac.params {
+                    write!(f, " {}", param)?;
+                }
+                writeln!(f)?;
+                for stmt in &mac.body {
+                    writeln!(f, "\t{}", stmt)?;
+                }
+                write!(f, ".endm")
+            },
+            Stmt::Rept(rept) => {
+                match &rept.kind {
+                    ReptKind::Count(count)        => write!(f, ".rept {}", count)?,
+                    ReptKind::Irp(param, values)  => {
+                        write!(f, ".irp {}", param)?;
+                        for value in values {
+                            write!(f, ", {}", value)?;
+                        }
+                    },
+                    ReptKind::Irpc(param, chars)  => write!(f, ".irpc {}, \"{}\"", param, chars)?,
+                }
+                writeln!(f)?;
+                for stmt in &rept.body {
+                    writeln!(f, "\t{}", stmt)?;
+                }
+                write!(f, ".endr")
+            },
+            Stmt::Call(call) => {
+                write!(f, "{} ", call.name)?;
+                for arg in &call.args {
+                    write!(f, "{} ", arg)?;
+                }
+                Ok(())
+            },
+            Stmt::Cond(cond, _) => match cond {
+                Cond::If(lit)                        => write!(f, ".if {}", lit),
+                Cond::IfDef { name, negated: false } => write!(f, ".ifdef {}", name),
+                Cond::IfDef { name, negated: true }  => write!(f, ".ifndef {}", name),
+                Cond::Elif(lit)                      => write!(f, ".elif {}", lit),
+                Cond::Else                           => write!(f, ".else"),
+                Cond::Endif                          => write!(f, ".endif"),
+            },
+            Stmt::Incbin(bin) => {
+                write!(f, ".incbin \"{}\" {}", bin.path, bin.format)?;
+                for lit in bin.offset.iter().chain(&bin.length) {
+                    write!(f, " {}", lit)?;
+                }
+                Ok(())
+            },
+            Stmt::Namespace(Some(name), _) => write!(f, ".namespace {}", name),
+            Stmt::Namespace(None, _)       => write!(f, ".end"),
+            Stmt::Global(names) => {
+                let names: Vec<_> = names.iter().map(|name| name.inner).collect();
+                write!(f, ".global {}", names.join(", "))
+            },
+            Stmt::Include(inc) if inc.library => write!(f, ".include <{}>", inc.path),
+            Stmt::Include(inc) => write!(f, ".include \"{}\"", inc.path),
         }
     }
 }
@@ -234,11 +512,15 @@ impl<'a> Display for Arg<'a> {
 impl<'a> Display for Lit<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Lit::Chr(chr) => write!(f, "'{}'", chr.inner),
+            Lit::Chr(chr) => write!(f, "'{}'", chr.inner.escape_default()),
             Lit::Str(s)   => write!(f, "\"{}\"", s.inner),
-            Lit::Num(num) => write!(f, "{}", num.inner),
+            Lit::Num(num) => write_num(f, num),
             Lit::Ref(r)   => write!(f, "&{}", r),
             Lit::Deref(d) => write!(f, "*{}", d),
+            Lit::Name(n)  => write!(f, "{}", n.inner),
+            Lit::Expr(e)  => write!(f, "({} {} {})", e.lhs, e.op, e.rhs),
+            Lit::Here(_)  => write!(f, "$"),
+            Lit::SizeOf(lit, _) => write!(f, "sizeof({})", lit),
             Lit::Lbl(Spanned { inner: (name, id), .. }) => {
                 write!(f, "'{}", name)?;
                 if *id > 0 {
@@ -250,6 +532,36 @@ impl<'a> Display for Lit<'a> {
     }
 }
 
+/// Writes a number in the base it was written in the source.
+fn write_num(f: &mut Formatter<'_>, num: &Num<'_>) -> fmt::Result {
+    let text = num.span.as_str();
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None         => ("", text),
+    };
+    // A negative number keeps its sign, otherwise all of the bits are written.
+    let val = if sign.is_empty() { num.inner as u32 } else { num.inner.unsigned_abs() };
+
+    match digits.get(..2) {
+        Some("0x") => write!(f, "{}0x{:x}", sign, val),
+        Some("0b") => write!(f, "{}0b{:b}", sign, val),
+        Some("0o") => write!(f, "{}0o{:o}", sign, val),
+        _          => write!(f, "{}", num.inner),
+    }
+}
+
+impl Display for IncbinFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IncbinFormat::Bytes => "bytes",
+            IncbinFormat::Tape  => "tape",
+            IncbinFormat::Chars => "chars",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
 impl Display for Op {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let name = match self {
@@ -274,6 +586,25 @@ impl Display for Op {
     }
 }
 
+impl Display for BinOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Eq  => "==",
+            BinOp::Ne  => "!=",
+            BinOp::Lt  => "<",
+            BinOp::Le  => "<=",
+            BinOp::Gt  => ">",
+            BinOp::Ge  => ">=",
+        };
+
+        write!(f, "{}", op)
+    }
+}
+
 impl<'a, T: Display> Display for Spanned<'a, T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         Display::fmt(&self.inner, f)
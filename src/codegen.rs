@@ -1,16 +1,20 @@
 #![allow(unused_macros)]
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::BTreeMap;
 
-use pest::Span;
-
-use crate::ast;
-use crate::parser::Error;
+use crate::ast::{ self, Span };
+use crate::parser::{ self, Error, Errors };
+use crate::lint::{ self, Lint, Lints, Level };
+use crate::macros;
+use crate::source::Sources;
+use crate::encoding::Encoding;
+use crate::machine;
 
 macro_rules! error {
     ($msg:expr, $span:expr) => {
-        Err(pest::error::Error::new_from_span(pest::error::ErrorVariant::CustomError { message: $msg.into() }, $span))
+        Err(Error::new($msg.into(), $span))
     };
 }
 
@@ -104,6 +108,16 @@ macro_rules! stmts {
 
 const EMPTY_DEFAULT: i32 = -1;
 
+// How many cell values are shown in each line of a listing.
+const LISTING_WIDTH: usize = 6;
+
+// How deep macros can expand inside of other macros, so that recursive macros don't go on forever.
+const MAX_MACRO_DEPTH: usize = 64;
+
+// How many times a block can be repeated, so that a huge count is reported instead of running out
+// of memory.
+const MAX_REPEAT: i32 = 1 << 20;
+
 type Result<T> = std::result::Result<T, Error>;
 
 type Position = usize;
@@ -119,7 +133,19 @@ impl<'a> Ident<'a> {
 
     #[inline]
     fn is_local(&self) -> bool {
-        self.0.starts_with(".")
+        self.0.starts_with(".") || self.is_numeric()
+    }
+
+    #[inline]
+    fn is_numeric(&self) -> bool {
+        self.0.starts_with(|c: char| c.is_ascii_digit())
+    }
+
+    /// Whether it is a global label used inside of a namespace or qualified with one, which is
+    /// only told apart from other labels by `solve_namespaces`.
+    #[inline]
+    fn is_namespaced(&self) -> bool {
+        !self.is_local() && (self.1 > 0 || self.0.contains("::"))
     }
 }
 
@@ -147,6 +173,21 @@ impl<'a> std::fmt::Display for Ident<'a> {
     }
 }
 
+fn undefined_label<'a, T>(lbl: Ident<'a>, span: Span<'a>) -> Result<T> {
+    if lbl.is_numeric() {
+        // The reference as written tells which way it looked for the label.
+        let dir = if span.as_str().ends_with('b') { "before" } else { "after" };
+        error!(format!("label '{} was not defined, there is no `{}:` {} it in its scope", span.as_str(), lbl.0, dir), span)
+    } else {
+        error!(format!("label \"{}\" was not defined", lbl.0), span)
+    }
+}
+
+// The global label of a name, without the local label it may be qualified with.
+fn global_part(name: &str) -> &str {
+    name.split_once('.').map_or(name, |(global, _)| global)
+}
+
 #[derive(PartialEq, Eq, Clone)]
 struct LabelDef<'a> {
     pos: Position,
@@ -190,6 +231,14 @@ impl<'a> std::fmt::Debug for LabelRef<'a> {
     }
 }
 
+/// A use of an expression, whose value is only known once every label is placed.
+struct ExprUse<'a> {
+    expr: ast::Lit<'a>,
+    // How many `&` the expression is inside of. Without any, the value goes right in the tape.
+    ref_lvl: u32,
+    at: LabelRef<'a>,
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 enum Auto<'a> {
     Lbl(Ident<'a>, u32),
@@ -288,6 +337,311 @@ impl<'a> std::fmt::Display for Auto<'a> {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LabelKind {
+    Global,
+    Local,
+    // A local label defined in an argument, like `<.arg>`.
+    Arg,
+    // Defined by the assembler, like `__end` or the labels generated by pseudo instructions.
+    Auto,
+    // Names an entry of the constant pool.
+    Pool,
+}
+
+impl std::fmt::Display for LabelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LabelKind::Global => "global",
+            LabelKind::Local  => "local",
+            LabelKind::Arg    => "arg",
+            LabelKind::Auto   => "auto",
+            LabelKind::Pool   => "pool",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The global label that encloses local labels, with the namespace it was defined in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Scope<'a> {
+    pub namespace: Option<&'a str>,
+    pub name: &'a str,
+}
+
+impl std::fmt::Display for Scope<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.namespace {
+            Some(ns) => write!(f, "{}::{}", ns, self.name),
+            None     => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A label as it was placed in the tape.
+#[derive(Debug, Clone)]
+pub struct LabelInfo<'a> {
+    pub name: String,
+    // The global label that encloses it, if it is local.
+    pub scope: Option<Scope<'a>>,
+    // Tells apart the definitions of a numeric label, which share their name in a scope.
+    pub id: usize,
+    pub pos: Position,
+    pub kind: LabelKind,
+    // Where it was defined, if it was defined in the source.
+    pub span: Option<Span<'a>>,
+}
+
+impl<'a> LabelInfo<'a> {
+    /// The name of the label prefixed by its enclosing global, e.g. `main.loop` or
+    /// `io::print_str.arg`.
+    pub fn qualified_name(&self) -> String {
+        match self.scope {
+            Some(scope) => format!("{}{}", scope, self.name),
+            None        => self.name.clone(),
+        }
+    }
+
+    // The name under which uses of the label are recorded.
+    fn used_name(&self) -> String {
+        match self.id {
+            0  => self.qualified_name(),
+            id => format!("{}_{:02x}", self.qualified_name(), id),
+        }
+    }
+}
+
+/// An instruction as it was placed in the tape.
+#[derive(Debug, Clone)]
+pub struct InstInfo<'a> {
+    pub op: ast::Op,
+    // The source that generated the instruction. For pseudo instructions this is the span of the
+    // pseudo instruction itself.
+    pub span: Span<'a>,
+    pub scope: Option<Scope<'a>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CellKind {
+    Opcode,
+    Arg,
+    Lit,
+    // Auto generated by the assembler from `&` literals.
+    Pool,
+}
+
+impl std::fmt::Display for CellKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            CellKind::Opcode => "opcode",
+            CellKind::Arg    => "arg",
+            CellKind::Lit    => "lit",
+            CellKind::Pool   => "pool",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Where the value of a cell came from.
+#[derive(Debug, Clone)]
+pub struct CellInfo<'a> {
+    pub kind: CellKind,
+    // For pool entries, this is the span of the first use.
+    pub span: Span<'a>,
+    pub scope: Option<Scope<'a>>,
+}
+
+/// Why `DebugInfo::lookup` found no single label.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LookupError {
+    Missing,
+    // More than one label has the name, like the definitions of a numeric label.
+    Ambiguous,
+}
+
+/// Everything the assembler knows about the program that is not in the tape itself.
+#[derive(Debug, Default)]
+pub struct DebugInfo<'a> {
+    pub labels: Vec<LabelInfo<'a>>,
+    pub insts: BTreeMap<Position, InstInfo<'a>>,
+    pub cells: BTreeMap<Position, CellInfo<'a>>,
+}
+
+impl<'a> DebugInfo<'a> {
+    /// Finds the position of a label. Local labels are resolved in `scope` unless they are
+    /// qualified by their global label, like `main.loop`. Inside of a namespace, the labels of the
+    /// namespace are looked for first.
+    pub fn lookup(&self, name: &str, scope: Option<Scope<'a>>) -> std::result::Result<Position, LookupError> {
+        if let Some(ns) = scope.and_then(|scope| scope.namespace) {
+            if !name.starts_with('.') && !name.contains("::") {
+                match self.find(&format!("{}::{}", ns, name), scope) {
+                    Err(LookupError::Missing) => {},
+                    res                       => return res,
+                }
+            }
+        }
+        self.find(name, scope)
+    }
+
+    fn find(&self, name: &str, scope: Option<Scope<'a>>) -> std::result::Result<Position, LookupError> {
+        let mut found = self.labels.iter().filter(|lbl| match name.find('.') {
+            Some(0) => lbl.name == name && lbl.scope == scope,
+            Some(i) => lbl.name == name[i..] && lbl.scope.is_some_and(|scope| scope.to_string() == name[..i]),
+            None    => lbl.name == name && lbl.scope.is_none(),
+        });
+        match (found.next(), found.next()) {
+            (Some(lbl), None)  => Ok(lbl.pos),
+            (Some(_), Some(_)) => Err(LookupError::Ambiguous),
+            (None, _)          => Err(LookupError::Missing),
+        }
+    }
+
+    /// All the labels defined at `pos`.
+    pub fn labels_at(&self, pos: Position) -> impl Iterator<Item = &LabelInfo<'a>> {
+        self.labels.iter().filter(move |lbl| lbl.pos == pos)
+    }
+
+    /// Writes the source map: one line per written cell, with its address, source location,
+    /// enclosing global label and kind, separated by tabs.
+    pub fn write_map<W: std::io::Write>(&self, out: &mut W, sources: &Sources) -> std::io::Result<()> {
+        writeln!(out, "# address\tfile\tline\tcolumn\tscope\tkind")?;
+        for (pos, cell) in &self.cells {
+            let (line, col) = cell.span.start_pos().line_col();
+            writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}",
+                pos, sources.path(cell.span.file).display(), line, col, cell.scope.map_or("-".to_string(), |scope| scope.to_string()), cell.kind)?;
+        }
+        Ok(())
+    }
+
+    /// All the labels sorted by address and then by name.
+    fn sorted_labels(&self) -> Vec<(String, &LabelInfo<'a>)> {
+        let mut labels: Vec<_> = self.labels
+            .iter()
+            .map(|lbl| (lbl.qualified_name(), lbl))
+            .collect();
+        labels.sort_by(|(a, lbl_a), (b, lbl_b)| lbl_a.pos.cmp(&lbl_b.pos).then(a.cmp(b)));
+        labels
+    }
+
+    /// Writes the symbol table: one line per label with its qualified name, address and kind,
+    /// separated by tabs.
+    pub fn write_symbols<W: std::io::Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "# name\taddress\tkind")?;
+        for (name, lbl) in self.sorted_labels() {
+            writeln!(out, "{}\t{}\t{}", name, lbl.pos, lbl.kind)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the symbol table as a JSON object from qualified names to addresses and kinds.
+    pub fn write_symbols_json<W: std::io::Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "{{")?;
+        let labels = self.sorted_labels();
+        for (i, (name, lbl)) in labels.iter().enumerate() {
+            let sep = if i + 1 < labels.len() { "," } else { "" };
+            writeln!(out, "  \"{}\": {{ \"address\": {}, \"kind\": \"{}\" }}{}",
+                json_escape(name), lbl.pos, lbl.kind, sep)?;
+        }
+        writeln!(out, "}}")
+    }
+
+    /// Writes a classic assembler listing of the program: every line along with the address and the
+    /// values of the cells it generated, followed by the constant pool. Included files are listed
+    /// right after their `.include`.
+    pub fn write_listing<W: std::io::Write>(&self, out: &mut W, sources: &Sources, tape: &[i32]) -> std::io::Result<()> {
+        let mut by_line: BTreeMap<(usize, usize), Vec<Position>> = BTreeMap::new();
+        let mut pool = Vec::new();
+        for (&pos, cell) in &self.cells {
+            if cell.kind == CellKind::Pool {
+                pool.push(pos);
+            } else {
+                let (line, _) = cell.span.start_pos().line_col();
+                by_line.entry((cell.span.file, line)).or_default().push(pos);
+            }
+        }
+
+        write_listing_file(out, sources, sources.main(), &by_line, tape)?;
+
+        if !pool.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "; constant pool")?;
+            for pos in pool {
+                let names: Vec<_> = self.labels_at(pos).map(LabelInfo::qualified_name).collect();
+                write_listing_row(out, &[pos], tape, &format!("       {}", names.join(" ")))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn sort_by_location(errors: &mut Errors) {
+    errors.sort_by_key(Error::location);
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"'  => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c    => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_listing_file<W: std::io::Write>(out: &mut W, sources: &Sources, file: usize,
+                                         by_line: &BTreeMap<(usize, usize), Vec<Position>>, tape: &[i32])
+    -> std::io::Result<()>
+{
+    let includes = sources.includes(file);
+    for (i, text) in sources.text(file).lines().enumerate() {
+        let cells = by_line.get(&(file, i + 1)).map(Vec::as_slice).unwrap_or(&[]);
+        let mut rows = listing_rows(cells);
+        let first = rows.next().unwrap_or(&[]);
+
+        write_listing_row(out, first, tape, &format!("{:>5}  {}", i + 1, text))?;
+        for row in rows {
+            write_listing_row(out, row, tape, "")?;
+        }
+
+        if let Some(&included) = includes.get(&(i + 1)) {
+            write_listing_row(out, &[], tape, &format!("       ; {}", sources.path(included).display()))?;
+            write_listing_file(out, sources, included, by_line, tape)?;
+            write_listing_row(out, &[], tape, &format!("       ; end of {}", sources.path(included).display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Splits the cells into runs of consecutive addresses with at most `LISTING_WIDTH` cells each.
+fn listing_rows(cells: &[Position]) -> impl Iterator<Item = &[Position]> {
+    let mut rest = cells;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut len = 1;
+        while len < rest.len() && len < LISTING_WIDTH && rest[len] == rest[len - 1] + 1 {
+            len += 1;
+        }
+
+        let (row, tail) = rest.split_at(len);
+        rest = tail;
+        Some(row)
+    })
+}
+
+fn write_listing_row<W: std::io::Write>(out: &mut W, cells: &[Position], tape: &[i32], text: &str) -> std::io::Result<()> {
+    let addr = cells.first().map(|pos| format!("{:04}", pos)).unwrap_or_default();
+    let vals: Vec<_> = cells.iter().map(|&pos| tape[pos].to_string()).collect();
+    let row = format!("{:<6}{:<36}{}", addr, vals.join(" "), text);
+    writeln!(out, "{}", row.trim_end())
+}
+
 pub struct Assembler<'a> {
     tape: Vec<i32>,
     pos: usize,
@@ -295,11 +649,51 @@ pub struct Assembler<'a> {
     labels: HashMap<Ident<'a>, LabelDef<'a>>,
     locals: HashMap<Ident<'a>, LabelDef<'a>>,
     lit_uses: BTreeMap<Auto<'a>, Vec<LabelRef<'a>>>,
+    exprs: Vec<ExprUse<'a>>,
+    // Where each `.org` left off, which ends the data of the label before it for `sizeof`.
+    org_ends: Vec<Position>,
     macro_count: usize,
+    // The last global label defined.
+    scope: Option<Scope<'a>>,
+    info: DebugInfo<'a>,
+    errors: Errors,
+    lints: Lints,
+    warnings: Errors,
+    // Qualified names of every label that was referenced.
+    used: HashSet<String>,
+    // Whether the tape grows to fit the program instead of having a fixed size.
+    grow: bool,
+    // The first cell that did not fit in the tape.
+    overflow: Option<Span<'a>>,
+    // How many cells the program needs.
+    needed: usize,
+    // The last cell that was overwritten and what overwrote it, so that a run of overwritten cells
+    // is reported only once.
+    last_overlap: Option<(Position, CellKind, usize)>,
+    macros: HashMap<&'a str, ast::Macro<'a>>,
+    constants: HashMap<&'a str, i32>,
+    // The files already included, which are not included again.
+    included: HashSet<usize>,
+    // The id of the last definition of each numeric label, so that every definition is a label
+    // of its own.
+    numeric_lbls: HashMap<&'a str, usize>,
+    // The id of every namespace, which is the id of the global labels defined in it.
+    namespaces: HashMap<&'a str, usize>,
+    // The global labels defined in every namespace, by its id.
+    ns_labels: HashSet<(usize, &'a str)>,
+    // The labels exported with `.global` by every namespace, by its id.
+    ns_exports: HashMap<(usize, &'a str), Span<'a>>,
+    // The namespace being assembled and its id.
+    namespace: Option<(&'a str, usize)>,
+    // How many macro expansions we are inside of.
+    depth: usize,
+    // The outermost macro use being expanded. Everything it generates is attributed to it.
+    call_site: Option<Span<'a>>,
+    encoding: Encoding,
 }
 
 impl<'a> Assembler<'a> {
-    pub fn new(tape_size: usize, expand: bool) -> Assembler<'a> {
+    pub fn new(tape_size: usize, expand: bool, lints: Lints, encoding: Encoding) -> Assembler<'a> {
         Assembler {
             tape: vec![0; tape_size],
             pos: 0,
@@ -307,10 +701,50 @@ impl<'a> Assembler<'a> {
             labels: HashMap::new(),
             locals: HashMap::new(),
             lit_uses: BTreeMap::new(),
+            exprs: Vec::new(),
+            org_ends: Vec::new(),
             macro_count: 0,
+            scope: None,
+            info: DebugInfo::default(),
+            errors: Vec::new(),
+            lints,
+            warnings: Vec::new(),
+            used: HashSet::new(),
+            grow: false,
+            overflow: None,
+            needed: 0,
+            last_overlap: None,
+            macros: HashMap::new(),
+            constants: HashMap::new(),
+            included: HashSet::new(),
+            numeric_lbls: HashMap::new(),
+            namespaces: HashMap::new(),
+            ns_labels: HashSet::new(),
+            ns_exports: HashMap::new(),
+            namespace: None,
+            depth: 0,
+            call_site: None,
+            encoding,
         }
     }
 
+    /// Finds the size of the smallest tape that fits the program, with the constants in `defines`.
+    pub fn fit(stmts: &[ast::Stmt<'a>], defines: &[(&'a str, i32)], encoding: Encoding)
+        -> std::result::Result<usize, Errors>
+    {
+        let mut asm = Assembler::new(0, false, Lints::allow_all(), encoding);
+        asm.grow = true;
+        for &(name, value) in defines {
+            asm.define(name, value);
+        }
+        asm.assemble(stmts).map(|tape| tape.len())
+    }
+
+    /// Defines a constant before the program is assembled, like the ones given in the command line.
+    pub fn define(&mut self, name: &'a str, value: i32) {
+        self.constants.insert(name, value);
+    }
+
     fn goto(&mut self, pos: usize) {
         self.pos = pos;
     }
@@ -319,44 +753,88 @@ impl<'a> Assembler<'a> {
         self.pos
     }
 
-    fn push_tape(&mut self, v: i32) {
+    fn push_tape(&mut self, v: i32, kind: CellKind, span: Span<'a>) {
+        let span = self.call_site.clone().unwrap_or(span);
+        self.needed = self.needed.max(self.pos + 1);
         if self.pos >= self.tape.len() {
-            panic!("TAPE SIZE EXCEEDED");
+            if self.grow {
+                self.tape.resize(self.pos + 1, 0);
+            } else {
+                // Keep going so that we know how many cells the program needs in the end.
+                if self.overflow.is_none() {
+                    self.overflow = Some(span);
+                }
+                self.pos += 1;
+                return;
+            }
+        }
+        if let Some(prev) = self.info.cells.get(&self.pos).cloned() {
+            self.report_overlap(kind, span.clone(), prev);
         }
         self.tape[self.pos] = v;
+        let scope = if kind == CellKind::Pool { None } else { self.scope };
+        self.info.cells.insert(self.pos, CellInfo { kind, span, scope });
         self.pos += 1;
     }
 
-    fn push_string(&mut self, s: &str, span: Span<'a>) -> Result<usize> {
-        let bytes = s.as_bytes();
-        let mut i = 0;
-        while i < bytes.len() {
-            if bytes[i] == b'\\' {
-                i += 1;
-                let escaped = match bytes[i] {
-                    b'n'  => b'\n',
-                    b'r'  => b'\r',
-                    b't'  => b'\t',
-                    b'\\' => b'\\',
-                    b'\'' => b'\'',
-                    b'0'  => b'\0',
-                    other => return error!(format!("invalid escape character '\\{}'", other as char), span),
-                };
-                self.push_tape(escaped as i32);
-            } else {
-                self.push_tape(bytes[i] as i32);
-            }
-            i += 1
+    /// Pushes an entry of the constant pool, naming it after `auto`.
+    fn push_pool(&mut self, v: i32, auto: Auto<'a>, span: Span<'a>) {
+        self.add_pool_lbl(auto, self.get_pos());
+        self.push_tape(v, CellKind::Pool, span);
+    }
+
+    fn add_pool_lbl(&mut self, auto: Auto<'a>, pos: Position) {
+        self.info.labels.push(LabelInfo {
+            name: auto.to_string().trim_start_matches('\'').to_string(),
+            scope: None,
+            id: 0,
+            pos,
+            kind: LabelKind::Pool,
+            span: None,
+        });
+    }
+
+    fn push_string(&mut self, s: &str, span: Span<'a>, kind: CellKind) -> Result<usize> {
+        let cells = self.encode_str(s, span.clone())?;
+        for &cell in &cells {
+            self.push_tape(cell, kind, span.clone());
         }
 
-        Ok(i)
+        Ok(cells.len())
+    }
+
+    /// The cells of a string literal, with its escapes replaced.
+    fn encode_str(&self, s: &str, span: Span<'a>) -> Result<Vec<i32>> {
+        let chars = parser::unescape(s).map_err(|msg| Error::new(msg, span))?;
+        Ok(self.encoding.encode_str(&chars))
     }
 
-    pub fn assemble(mut self, stmts: &[ast::Stmt<'a>]) -> Result<Vec<i32>> {
-        self.assemble_stmts(stmts)?;
+    /// Assembles the program, ignoring its warnings.
+    pub fn assemble(self, stmts: &[ast::Stmt<'a>]) -> std::result::Result<Vec<i32>, Errors> {
+        self.assemble_with_info(stmts).0.map(|(tape, _)| tape)
+    }
+
+    /// Assembles the program, reporting every error found instead of stopping at the first one. The
+    /// warnings are returned separately, since they are found even when assembling fails.
+    pub fn assemble_with_info(mut self, stmts: &[ast::Stmt<'a>])
+        -> (std::result::Result<(Vec<i32>, DebugInfo<'a>), Errors>, Errors)
+    {
+        let stmts = &self.select(stmts);
+        self.predefine(stmts);
+        self.assemble_stmts(stmts);
+
+        let mut found = Vec::new();
+        lint::check_stmts(stmts, |lint, msg: &str, span| found.push((lint, msg.to_string(), span)));
+        for (lint, msg, span) in found {
+            self.lint(lint, &msg, span);
+        }
 
-        self.solve_locals()?;
-        self.add_auto_lbls()?;
+        self.solve_namespaces();
+        let res = self.solve_locals();
+        self.report(res);
+        let res = self.add_auto_lbls();
+        self.report(res);
+        self.solve_exprs();
 
         #[derive(PartialEq, Eq)]
         enum SolveState {
@@ -369,28 +847,33 @@ impl<'a> Assembler<'a> {
         let mut prev_lvl = 0;
         let mut prev_val = 0;
 
-        let mut prev_lbl_name = "__none";
+        let mut prev_lbl = Ident("__none", 0);
         let mut prev_num = 0;
         let mut prev_str = "";
 
         for (auto, uses) in std::mem::take(&mut self.lit_uses) {
             match auto {
                 Auto::Lbl(lbl, ref_lvl) => {
-                    if prev_lbl_name != lbl.0 {
+                    if prev_lbl != lbl {
                         prev_lvl = 0;
-                        if let Some(def) = self.labels.get(&lbl) {
-                            prev_val = def.pos as i32;
+                        let res = self.label_pos(lbl, uses[0].span.clone());
+                        if let Some(pos) = self.report(res) {
+                            prev_val = pos as i32;
                         } else {
-                            return error!(format!("label \"{}\" was not defined", lbl.0), uses[0].span.clone());
+
+                            // Other uses of the same label should not be reported again.
+                            prev_lbl = lbl;
+                            prev_lvl = ref_lvl;
+                            continue;
                         }
                     }
 
-                    for _ in prev_lvl + 1..=ref_lvl {
+                    for lvl in prev_lvl + 1..=ref_lvl {
                         let pos = self.get_pos();
-                        self.push_tape(prev_val);
+                        self.push_pool(prev_val, Auto::Lbl(lbl, lvl), uses[0].span.clone());
                         prev_val = pos as i32;
                     }
-                    prev_lbl_name = lbl.0;
+                    prev_lbl = lbl;
                     prev_lvl = ref_lvl;
                 },
                 Auto::Num(num, ref_lvl) => {
@@ -400,9 +883,9 @@ impl<'a> Assembler<'a> {
                         prev_lvl = 0;
                     }
 
-                    for _ in prev_lvl + 1..=ref_lvl {
+                    for lvl in prev_lvl + 1..=ref_lvl {
                         let pos = self.get_pos();
-                        self.push_tape(prev_val);
+                        self.push_pool(prev_val, Auto::Num(num, lvl), uses[0].span.clone());
                         prev_val = pos as i32;
                     }
                     prev_lvl = ref_lvl;
@@ -413,14 +896,16 @@ impl<'a> Assembler<'a> {
                         prev_state = SolveState::Strs;
                         prev_val = self.get_pos() as i32;
 
-                        self.push_string(s, uses[0].span.clone())?;
+                        self.add_pool_lbl(Auto::Str(s, 1), self.get_pos());
+                        let res = self.push_string(s, uses[0].span.clone(), CellKind::Pool);
+                        self.report(res);
 
                         prev_lvl = 1;
                     }
 
-                    for _ in prev_lvl + 1..=ref_lvl {
+                    for lvl in prev_lvl + 1..=ref_lvl {
                         let pos = self.get_pos();
-                        self.push_tape(prev_val);
+                        self.push_pool(prev_val, Auto::Str(s, lvl), uses[0].span.clone());
                         prev_val = pos as i32;
                     }
                     prev_lvl = ref_lvl;
@@ -430,31 +915,137 @@ impl<'a> Assembler<'a> {
 
             // println!("Solving uses of {}, {:?}", auto, uses);
             for lbl_ref in &uses {
-                self.tape[lbl_ref.pos] = prev_val;
+                // Uses that did not fit in the tape were already reported.
+                if let Some(cell) = self.tape.get_mut(lbl_ref.pos) {
+                    *cell = prev_val;
+                }
             }
         }
 
-        Ok(self.tape)
+        if let Some(span) = self.overflow.take() {
+            let res: Result<()> = error!(format!("tape size exceeded: the program needs {} cells but the tape has {}",
+                                                 self.needed, self.tape.len()), span);
+            self.report(res);
+        }
+
+        self.lint_unused_labels();
+
+        sort_by_location(&mut self.warnings);
+        let res = if self.errors.is_empty() {
+            Ok((self.tape, self.info))
+        } else {
+            sort_by_location(&mut self.errors);
+            Err(self.errors)
+        };
+        (res, self.warnings)
+    }
+
+    fn lint(&mut self, lint: Lint, msg: &str, span: Span<'a>) {
+        match self.lints.diagnostic(lint, msg, span) {
+            Some((Level::Deny, err)) => self.errors.push(err),
+            Some((_, err))           => self.warnings.push(err),
+            None                     => (),
+        }
+    }
+
+    fn report_overlap(&mut self, kind: CellKind, span: Span<'a>, prev: CellInfo<'a>) {
+        let curr = (self.pos, kind, span.start());
+        let continues = match self.last_overlap {
+            Some((pos, last_kind, start)) =>
+                pos + 1 == self.pos && (start == span.start() || last_kind == CellKind::Pool && kind == CellKind::Pool),
+            None => false,
+        };
+        self.last_overlap = Some(curr);
+        if continues {
+            return;
+        }
+
+        let what = if kind == CellKind::Pool { "constant pool overwrites" } else { "overwrites" };
+        let msg = format!("{} cell {}, which was already written", what, self.pos);
+        let note = format!("cell {} was first written here", self.pos);
+        match self.lints.diagnostic_with_note(Lint::OrgOverlap, &msg, span, Some((&note, prev.span))) {
+            Some((Level::Deny, err)) => self.errors.push(err),
+            Some((_, err))           => self.warnings.push(err),
+            None                     => (),
+        }
     }
 
-    fn assemble_stmts(&mut self, stmts: &[ast::Stmt<'a>]) -> Result<usize> {
+    fn lint_unused_labels(&mut self) {
+        let unused: Vec<_> = self.info.labels
+            .iter()
+            .filter(|lbl| matches!(lbl.kind, LabelKind::Global | LabelKind::Local | LabelKind::Arg))
+            // By convention `main` is where the program starts, so it need not be referenced.
+            .filter(|lbl| lbl.name != "main" && !self.used.contains(&lbl.used_name()))
+            .filter_map(|lbl| Some((lbl.qualified_name(), lbl.span.clone()?)))
+            .collect();
+
+        for (name, span) in unused {
+            self.lint(Lint::UnusedLabel, &format!("label \"{}\" is never used", name), span);
+        }
+    }
+
+    /// Marks a label as referenced, qualifying local labels with the current scope.
+    fn mark_used(&mut self, ident: Ident<'a>) {
+        let scope = self.scope.map_or(String::new(), |scope| scope.to_string());
+        let name = if ident.is_numeric() {
+            format!("{}.{}", scope, ident)
+        } else if ident.is_local() {
+            format!("{}{}", scope, ident)
+        } else {
+            // A local label of another scope also uses the global label of that scope.
+            if let Some((scope, _)) = ident.0.split_once('.') {
+                let scope = self.global_name(Ident::new(scope, ident.1));
+                self.used.insert(scope);
+            }
+            self.global_name(ident)
+        };
+        self.used.insert(name);
+    }
+
+    /// Keeps the error to be reported once assembling is done, so that it can go on.
+    fn report<T>(&mut self, res: Result<T>) -> Option<T> {
+        match res {
+            Ok(val) => Some(val),
+            Err(e)  => {
+                self.errors.push(e);
+                None
+            },
+        }
+    }
+
+    fn assemble_stmts(&mut self, stmts: &[ast::Stmt<'a>]) -> usize {
         let mut count = 0;
         for stmt in stmts {
-            count += self.assemble_stmt(stmt)?;
+            let res = self.assemble_stmt(stmt);
+            count += self.report(res).unwrap_or(0);
         }
-        Ok(count)
+        count
     }
 
     fn assemble_stmt(&mut self, stmt: &ast::Stmt<'a>) -> Result<usize> {
         use ast::Stmt::*;
 
         match stmt {
+            Label(lbl) if Ident::from(lbl.inner).is_numeric() => {
+                let id = self.numeric_lbls.entry(lbl.0).or_default();
+                *id += 1;
+                let lbl = ast::Spanned::new((lbl.0, *id), lbl.span());
+                self.add_local_lbl_to(&lbl, self.get_pos(), LabelKind::Local)?;
+                Ok(0)
+            },
             Label(lbl) if Ident::from(lbl.inner).is_local() => {
                 self.add_local_lbl(lbl)?;
                 Ok(0)
             },
             Label(lbl) => {
+                let lbl = &match self.namespace {
+                    Some((_, id)) => ast::Spanned::new((lbl.0, id), lbl.span()),
+                    None          => lbl.clone(),
+                };
                 self.add_global_lbl(lbl)?;
+                if let Some((_, id)) = self.namespace {
+                    self.ns_labels.insert((id, lbl.0));
+                }
                 if self.expand {
                     print!("{}", lbl.0);
                     if lbl.1 > 0 {
@@ -464,27 +1055,708 @@ impl<'a> Assembler<'a> {
                 }
                 Ok(0)
             },
-            Inst(inst) => self.assemble_inst(inst),
+            Inst(inst) => self.assemble_inst(&ast::Inst::new(inst.op, self.resolve_args(&inst.args)?, inst.span.clone())),
             Lit(lit)   => {
-                if self.expand {
-                    if self.labels.len() > 0 {
-                        print!("\t");
+                self.print_expanded(stmt);
+                self.assemble_lit(&self.resolve(lit)?)
+            },
+            Org(lit)  => {
+                let val = self.const_value(&self.resolve(lit)?)?;
+                self.org_ends.push(self.get_pos());
+                if val < 0 {
+                    if self.grow {
+                        return error!("`.org` relative to the end of the tape needs a fixed tape size", lit.span());
+                    }
+                    match self.tape.len().checked_sub(val.unsigned_abs() as usize) {
+                        Some(pos) => self.goto(pos),
+                        None      => return error!(format!("`.org` goes before the start of the tape, which has {} cells",
+                                                           self.tape.len()), lit.span()),
                     }
-                    println!("{}", lit);
+                } else {
+                    self.goto(val as usize);
                 }
-                self.assemble_lit(lit)
+                Ok(0)
             },
-            Org(val)  => {
-                if val.inner < 0 {
-                    self.goto(self.tape.len() + val.inner as usize);
-                } else {
-                    self.goto(val.inner as usize);
+            // Already defined by `predefine`.
+            Macro(_)   => Ok(0),
+            // Already defined by `select`.
+            Equ(_)     => Ok(0),
+            Cond(..)   => unreachable!("conditions are resolved by `select`"),
+            Fill(fill) => {
+                self.print_expanded(stmt);
+                let count = self.const_value(&self.resolve(&fill.count)?)?;
+                if count < 0 {
+                    return error!(format!("cannot repeat a value {} times", count), fill.count.span());
                 }
+
+                let zero = ast::Lit::Num(ast::Spanned::new(0, fill.span.clone()));
+                let value = fill.value.as_ref().unwrap_or(&zero);
+                let mut total = 0;
+                for _ in 0..count {
+                    // Every copy is a value of its own, so `$` is a different position in each.
+                    total += self.assemble_lit(&self.resolve(value)?)?;
+                }
+                Ok(total)
+            },
+            Word(lits) => {
+                self.print_expanded(stmt);
+                let mut total = 0;
+                for lit in lits {
+                    total += self.assemble_lit(&self.resolve(lit)?)?;
+                }
+                Ok(total)
+            },
+            // Like `.include`, embedded files are read when loading the program.
+            Incbin(bin) if self.depth > 0 => error!("`.incbin` is not allowed inside of macros or repetitions", bin.span.clone()),
+            Incbin(bin) => {
+                self.print_expanded(stmt);
+                let cells = self.incbin_cells(bin)?;
+                for &cell in &cells {
+                    self.push_tape(cell, CellKind::Lit, bin.span.clone());
+                }
+                Ok(cells.len())
+            },
+            Call(call) => self.expand_macro(call),
+            Rept(rept) => self.repeat(rept),
+            Namespace(_, span) if self.depth > 0 =>
+                error!("namespaces are not allowed inside of macros or repetitions", span.clone()),
+            Global(names) if self.depth > 0 =>
+                error!("`.global` is not allowed inside of macros or repetitions", names[0].span()),
+            // Already checked by `predefine`.
+            Namespace(Some(name), _) => {
+                self.namespace = Some((name.inner, self.namespaces[name.inner]));
                 Ok(0)
             },
+            Namespace(None, _) => {
+                self.namespace = None;
+                Ok(0)
+            },
+            Global(_)  => Ok(0),
+            // Loaded files are included by `select`, so this can only be inside of a macro or of a
+            // repetition, where files are never loaded.
+            Include(inc) => error!("`.include` is not allowed inside of macros or repetitions", inc.path.span()),
         }
     }
 
+    /// The cells of an embedded file, from `offset` and at most `length` of them.
+    fn incbin_cells(&self, bin: &ast::Incbin<'a>) -> Result<Vec<i32>> {
+        use ast::IncbinFormat;
+
+        let name = bin.path.inner;
+        let data = match &bin.data {
+            Some(data) => data.as_ref().map_err(Error::clone)?,
+            None       => unreachable!("files embedded at the top level are always loaded"),
+        };
+        let text = || std::str::from_utf8(data)
+            .map_err(|e| Error::new(format!("`{}` is not valid UTF-8: {}", name, e), bin.path.span()));
+        let cells: Vec<i32> = match bin.format {
+            IncbinFormat::Bytes => data.iter().map(|&b| i32::from(b)).collect(),
+            IncbinFormat::Chars => text()?.chars().map(|c| c as i32).collect(),
+            IncbinFormat::Tape  => machine::parse_tape(text()?)
+                .map_err(|e| Error::new(format!("`{}` is not a tape, {}", name, e), bin.path.span()))?,
+        };
+
+        let bound = |lit: &Option<ast::Lit<'a>>, what: &str, default: usize, max: usize| -> Result<usize> {
+            let lit = match lit {
+                Some(lit) => lit,
+                None      => return Ok(default),
+            };
+            let val = self.const_value(&self.resolve(lit)?)?;
+            if val < 0 || val as usize > max {
+                return error!(format!("the {} must be between 0 and {}, but it is {}", what, max, val), lit.span());
+            }
+            Ok(val as usize)
+        };
+        let offset = bound(&bin.offset, "offset", 0, cells.len())?;
+        let length = bound(&bin.length, "length", cells.len() - offset, cells.len() - offset)?;
+        Ok(cells[offset..offset + length].to_vec())
+    }
+
+    /// Prints a data statement for `--expand`.
+    fn print_expanded(&self, stmt: &ast::Stmt<'a>) {
+        if self.expand {
+            if !self.labels.is_empty() {
+                print!("\t");
+            }
+            println!("{}", stmt);
+        }
+    }
+
+    /// Macros can be used before being defined, so they are all collected before assembling. So
+    /// are the namespaces and what they export, which can't be inside of macros.
+    fn predefine(&mut self, stmts: &[ast::Stmt<'a>]) {
+        use ast::Stmt;
+
+        let mut namespace: Option<(usize, &Span<'a>)> = None;
+        for stmt in stmts {
+            let res = match stmt {
+                Stmt::Macro(def) => self.define_macro(def),
+                Stmt::Namespace(Some(name), span) => {
+                    let id = match self.namespaces.get(name.inner) {
+                        Some(&id) => id,
+                        None      => self.unique_ident(name.inner).1,
+                    };
+                    self.namespaces.insert(name.inner, id);
+                    if namespace.is_some() {
+                        error!("namespaces cannot be nested", span.clone())
+                    } else {
+                        namespace = Some((id, span));
+                        Ok(())
+                    }
+                },
+                Stmt::Namespace(None, span) => match namespace.take() {
+                    Some(_) => Ok(()),
+                    None    => error!("`.end` without a matching `.namespace`", span.clone()),
+                },
+                Stmt::Global(names) => match namespace {
+                    Some((id, _)) => {
+                        self.ns_exports.extend(names.iter().map(|name| ((id, name.inner), name.span())));
+                        Ok(())
+                    },
+                    None          => error!("`.global` is only allowed inside of a namespace", names[0].span()),
+                },
+                _ => continue,
+            };
+            self.report(res);
+        }
+
+        if let Some((_, span)) = namespace {
+            let res: Result<()> = error!("`.namespace` without a matching `.end`", span.clone());
+            self.report(res);
+        }
+    }
+
+    /// Keeps only the statements in the branches of `.if` blocks that are taken. Constants are
+    /// defined here, in order, so that a condition can use the constants defined before it. Those
+    /// defined by a macro only exist once the macro is expanded.
+    fn select(&mut self, stmts: &[ast::Stmt<'a>]) -> Vec<ast::Stmt<'a>> {
+        use ast::{ Cond, Stmt };
+
+        struct Block<'a> {
+            start: Span<'a>,
+            // Whether the statements of the current branch are kept.
+            taken: bool,
+            // Whether no other branch can be taken, because one already was or because the whole
+            // block is skipped.
+            done: bool,
+            has_else: bool,
+        }
+
+        let mut blocks: Vec<Block<'a>> = Vec::new();
+        let mut selected = Vec::new();
+        for stmt in stmts {
+            let active = blocks.last().is_none_or(|block| block.taken);
+            let (cond, span) = match stmt {
+                Stmt::Cond(cond, span) => (cond, span),
+                _ if !active           => continue,
+                Stmt::Equ(equ)         => {
+                    let res = self.define_constant(equ);
+                    self.report(res);
+                    selected.push(stmt.clone());
+                    continue;
+                },
+                // A file is only included once, so that every file can include what it uses.
+                Stmt::Include(ast::Include { file: Some(included), .. }) => {
+                    match included {
+                        ast::Included::File(file, stmts) => if self.included.insert(*file) {
+                            match stmts {
+                                Ok(stmts)   => selected.extend(self.select(stmts)),
+                                Err(errors) => self.errors.extend(errors.iter().cloned()),
+                            }
+                        },
+                        ast::Included::Failed(err)       => self.errors.push(err.clone()),
+                    }
+                    continue;
+                },
+                _                      => {
+                    selected.push(stmt.clone());
+                    continue;
+                },
+            };
+
+            let res = match (cond, blocks.last_mut()) {
+                (Cond::If(_) | Cond::IfDef { .. }, _) if !active => {
+                    blocks.push(Block { start: span.clone(), taken: false, done: true, has_else: false });
+                    Ok(())
+                },
+                // If the condition is wrong, no branch is taken, but the block is still there.
+                (Cond::If(lit), _) => {
+                    let res = self.condition(lit);
+                    let (taken, done) = res.as_ref().map_or((false, true), |&taken| (taken, taken));
+                    blocks.push(Block { start: span.clone(), taken, done, has_else: false });
+                    res.map(|_| ())
+                },
+                (Cond::IfDef { name, negated }, _) => {
+                    let taken = self.constants.contains_key(name.inner) != *negated;
+                    blocks.push(Block { start: span.clone(), taken, done: taken, has_else: false });
+                    Ok(())
+                },
+                (Cond::Elif(_), Some(block)) if block.has_else => error!("`.elif` after `.else`", span.clone()),
+                (Cond::Elif(_), Some(block)) if block.done => {
+                    block.taken = false;
+                    Ok(())
+                },
+                (Cond::Elif(lit), Some(_)) => {
+                    let res = self.condition(lit);
+                    let block = blocks.last_mut().unwrap();
+                    (block.taken, block.done) = res.as_ref().map_or((false, true), |&taken| (taken, taken));
+                    res.map(|_| ())
+                },
+                (Cond::Else, Some(block)) if block.has_else => error!("`.else` after `.else`", span.clone()),
+                (Cond::Else, Some(block)) => {
+                    block.taken = !block.done;
+                    block.done = true;
+                    block.has_else = true;
+                    Ok(())
+                },
+                (Cond::Endif, Some(_)) => {
+                    blocks.pop();
+                    Ok(())
+                },
+                (Cond::Elif(_), None) => error!("`.elif` without `.if`", span.clone()),
+                (Cond::Else, None)    => error!("`.else` without `.if`", span.clone()),
+                (Cond::Endif, None)   => error!("`.endif` without `.if`", span.clone()),
+            };
+            self.report(res);
+        }
+
+        for block in blocks {
+            let res: Result<()> = error!("`.if` without `.endif`", block.start);
+            self.report(res);
+        }
+        selected
+    }
+
+    /// Whether the branch of an `.if` or `.elif` is taken, which is when its value is not zero.
+    fn condition(&self, lit: &ast::Lit<'a>) -> Result<bool> {
+        Ok(self.const_value(lit)? != 0)
+    }
+
+    fn define_constant(&mut self, equ: &ast::Equ<'a>) -> Result<()> {
+        use std::collections::hash_map::Entry::*;
+
+        let value = self.const_value(&equ.value)?;
+        match self.constants.entry(equ.name.inner) {
+            Occupied(_)   => error!("constant defined twice", equ.name.span()),
+            Vacant(entry) => {
+                entry.insert(value);
+                Ok(())
+            },
+        }
+    }
+
+    /// The value of a literal that must be known right away, like the value of a constant.
+    fn const_value(&self, lit: &ast::Lit<'a>) -> Result<i32> {
+        self.eval(lit, &|lit| error!("expected a number or a constant", lit.span()))
+    }
+
+    /// Evaluates a number, a constant or an expression of those. The value of anything else, like
+    /// a label, is found by `other`.
+    fn eval(&self, lit: &ast::Lit<'a>, other: &dyn Fn(&ast::Lit<'a>) -> Result<i32>) -> Result<i32> {
+        use ast::{ BinOp, Lit };
+
+        match lit {
+            Lit::Num(num)   => Ok(num.inner),
+            Lit::Chr(chr)   => Ok(chr.inner as i32),
+            Lit::Name(name) => self.constant(name),
+            Lit::SizeOf(box Lit::Str(s), _) => Ok(self.encode_str(s.inner, s.span())?.len() as i32),
+            Lit::Expr(expr) => {
+                let lhs = self.eval(&expr.lhs, other)?;
+                let rhs = self.eval(&expr.rhs, other)?;
+                let val = match expr.op {
+                    BinOp::Add             => lhs.checked_add(rhs),
+                    BinOp::Sub             => lhs.checked_sub(rhs),
+                    BinOp::Mul             => lhs.checked_mul(rhs),
+                    BinOp::Div if rhs == 0 => return error!("division by zero", expr.span.clone()),
+                    BinOp::Div             => lhs.checked_div(rhs),
+                    BinOp::Eq              => Some((lhs == rhs) as i32),
+                    BinOp::Ne              => Some((lhs != rhs) as i32),
+                    BinOp::Lt              => Some((lhs < rhs) as i32),
+                    BinOp::Le              => Some((lhs <= rhs) as i32),
+                    BinOp::Gt              => Some((lhs > rhs) as i32),
+                    BinOp::Ge              => Some((lhs >= rhs) as i32),
+                };
+                match val {
+                    Some(val) => Ok(val),
+                    None      => error!("the value of this expression does not fit in a cell", expr.span.clone()),
+                }
+            },
+            _               => other(lit),
+        }
+    }
+
+    /// Replaces `$` with the position of the statement being assembled, which is where its first
+    /// cell goes, numeric labels with the definition they refer to from there and global labels
+    /// with the ones of their namespace.
+    fn resolve(&self, lit: &ast::Lit<'a>) -> Result<ast::Lit<'a>> {
+        use ast::Lit;
+
+        let resolved = match lit {
+            Lit::Here(span)       => Lit::Num(ast::Spanned::new(self.get_pos() as i32, span.clone())),
+            Lit::Lbl(lbl) if lbl.1 == 0 && Ident::from(lbl.inner).is_numeric() => {
+                let (name, dir) = lbl.0.split_at(lbl.0.len() - 1);
+                let last = self.numeric_lbls.get(name).copied().unwrap_or(0);
+                // `'1b` before any `1:` is never defined, since ids start at 1.
+                let id = if dir == "f" { last + 1 } else { last };
+                Lit::Lbl(ast::Spanned::new((name, id), lbl.span()))
+            },
+            Lit::Lbl(lbl) if lbl.1 == 0 && !Ident::from(lbl.inner).is_local() => Lit::Lbl(self.resolve_global(lbl)?),
+            Lit::SizeOf(box s, span) => Lit::SizeOf(Box::new(self.resolve(s)?), span.clone()),
+            Lit::Ref(box r)       => Lit::Ref(Box::new(self.resolve(r)?)),
+            Lit::Deref(box d)     => Lit::Deref(Box::new(self.resolve(d)?)),
+            Lit::Expr(box expr)   => Lit::Expr(Box::new(ast::Expr {
+                op: expr.op,
+                lhs: self.resolve(&expr.lhs)?,
+                rhs: self.resolve(&expr.rhs)?,
+                span: expr.span.clone(),
+            })),
+            other                 => other.clone(),
+        };
+        Ok(resolved)
+    }
+
+    fn resolve_args(&self, args: &[ast::Arg<'a>]) -> Result<Vec<ast::Arg<'a>>> {
+        args.iter()
+            .map(|arg| match arg {
+                ast::Arg::Lit(lit) => Ok(ast::Arg::Lit(self.resolve(lit)?)),
+                ast::Arg::Lbl(_)   => Ok(arg.clone()),
+            })
+            .collect()
+    }
+
+    /// Gives a global label the id of the namespace it is used in. Which label it is can only be
+    /// told once every label is defined, since macros and repetitions define labels too, so that
+    /// is left to `solve_namespaces`.
+    fn resolve_global(&self, lbl: &ast::Label<'a>) -> Result<ast::Label<'a>> {
+        if let Some((ns, _)) = lbl.0.split_once("::") {
+            if !self.namespaces.contains_key(ns) {
+                return error!(format!("there is no namespace named `{}`", ns), lbl.span());
+            }
+        }
+        let id = self.namespace.map_or(0, |(_, id)| id);
+        Ok(ast::Spanned::new((lbl.0, id), lbl.span()))
+    }
+
+    /// `ns::name` is the label `name` of the namespace `ns`, which must be exported unless it is
+    /// used inside of `ns`. Inside of a namespace, a plain name is the label of the namespace if
+    /// it has one, or else a label outside of any namespace. The same goes for the local labels
+    /// of those labels, like `ns::name.local`.
+    fn solve_ns_label(&mut self, lbl: Ident<'a>, span: Span<'a>) -> Result<Ident<'a>> {
+        let (ns, name) = match lbl.0.split_once("::") {
+            Some(qualified) => qualified,
+            None if lbl.1 > 0 && !self.ns_labels.contains(&(lbl.1, global_part(lbl.0))) => {
+                let outer = Ident::new(lbl.0, 0);
+                self.mark_used(outer);
+                return Ok(outer);
+            },
+            None            => return Ok(lbl),
+        };
+
+        let id = self.namespaces[ns];
+        let global = global_part(name);
+        if !self.ns_labels.contains(&(id, global)) {
+            return error!(format!("namespace `{}` has no label named `{}`", ns, global), span);
+        }
+        if lbl.1 != id && !self.ns_exports.contains_key(&(id, global)) {
+            return error!(format!("`{0}` is private to namespace `{1}`, export it with `.global {0}`", global, ns), span);
+        }
+        Ok(Ident::new(name, id))
+    }
+
+    /// Replaces the global labels used in namespaces, or qualified with one, with the labels they
+    /// refer to, now that every label of every namespace is defined.
+    fn solve_namespaces(&mut self) {
+        use std::collections::btree_map::Entry::*;
+
+        let exports: Vec<_> = self.ns_exports
+            .iter()
+            .filter(|(lbl, _)| !self.ns_labels.contains(lbl))
+            .map(|(&(_, name), span)| (name, span.clone()))
+            .collect();
+        for (name, span) in exports {
+            let res: Result<()> = error!(format!("`{}` is not a label of this namespace", name), span);
+            self.report(res);
+        }
+
+        let mut exprs = std::mem::take(&mut self.exprs);
+        for expr in &mut exprs {
+            self.solve_expr_namespaces(&mut expr.expr);
+        }
+        self.exprs = exprs;
+
+        let lbl_uses: Vec<_> = self.lit_uses
+            .extract_if(.., |auto, _| matches!(auto, Auto::Lbl(lbl, _) if lbl.is_namespaced()))
+            .collect();
+        for (auto, uses) in lbl_uses {
+            let (lbl, ref_lvl) = auto.unwrap_lbl();
+            let res = self.solve_ns_label(lbl, uses[0].span.clone());
+            let lbl = match self.report(res) {
+                Some(lbl) => lbl,
+                None      => continue,
+            };
+            match self.lit_uses.entry(Auto::Lbl(lbl, ref_lvl)) {
+                Occupied(entry) => entry.into_mut().extend(uses),
+                Vacant(entry)   => { entry.insert(uses); },
+            }
+        }
+    }
+
+    fn solve_expr_namespaces(&mut self, lit: &mut ast::Lit<'a>) {
+        use ast::Lit;
+
+        let span = lit.span();
+        let lbl = match lit {
+            Lit::Lbl(lbl)                     => lbl,
+            Lit::SizeOf(box Lit::Lbl(lbl), _) => lbl,
+            Lit::Expr(box expr)               => {
+                self.solve_expr_namespaces(&mut expr.lhs);
+                self.solve_expr_namespaces(&mut expr.rhs);
+                return;
+            },
+            _                                 => return,
+        };
+
+        if !Ident::from(lbl.inner).is_namespaced() {
+            return;
+        }
+        let res = self.solve_ns_label(Ident::from(lbl.inner), lbl.span());
+        match self.report(res) {
+            Some(Ident(name, id)) => lbl.inner = (name, id),
+            // Already reported, so the expression is not evaluated with it.
+            None                  => *lit = Lit::Num(ast::Spanned::new(0, span)),
+        }
+    }
+
+    /// The name of a global label, qualified with its namespace if it has one.
+    fn global_name(&self, ident: Ident<'a>) -> String {
+        if ident.0.contains("::") {
+            return ident.0.to_string();
+        }
+        match self.namespaces.iter().find(|&(_, &id)| id == ident.1) {
+            Some((ns, _)) => format!("{}::{}", ns, ident.0),
+            None          => ident.to_string(),
+        }
+    }
+
+    /// How many cells there are from `pos` up to the next label or `.org`, or up to the end of the
+    /// program.
+    fn size_from(&self, pos: Position) -> i32 {
+        let next = self.info.labels
+            .iter()
+            .filter(|lbl| matches!(lbl.kind, LabelKind::Global | LabelKind::Local))
+            .map(|lbl| lbl.pos)
+            .chain(self.org_ends.iter().copied())
+            .filter(|&end| end > pos)
+            .min()
+            .unwrap_or_else(|| self.get_pos());
+        next.saturating_sub(pos) as i32
+    }
+
+    /// Keeps an expression to be evaluated once every label is placed. Its value is written at
+    /// `pos`, or a reference to it if it is inside of `&`.
+    fn use_expr(&mut self, lit: &ast::Lit<'a>, ref_lvl: u32, pos: Position) {
+        self.mark_expr_used(lit);
+        self.exprs.push(ExprUse { expr: lit.clone(), ref_lvl, at: LabelRef::new(pos, lit.span()) });
+    }
+
+    fn mark_expr_used(&mut self, lit: &ast::Lit<'a>) {
+        match lit {
+            ast::Lit::Lbl(lbl) |
+            ast::Lit::SizeOf(box ast::Lit::Lbl(lbl), _) => self.mark_used(Ident::from(lbl.inner)),
+            ast::Lit::Expr(expr) => {
+                self.mark_expr_used(&expr.lhs);
+                self.mark_expr_used(&expr.rhs);
+            },
+            _                    => (),
+        }
+    }
+
+    /// Replaces the local labels of the scope that just ended with their positions, since they can't
+    /// be found once the scope is gone.
+    fn solve_expr_locals(&mut self, lit: &mut ast::Lit<'a>) {
+        use ast::Lit;
+
+        let (lbl, sized) = match lit {
+            Lit::Lbl(lbl)                     => (lbl.clone(), false),
+            Lit::SizeOf(box Lit::Lbl(lbl), _) => (lbl.clone(), true),
+            Lit::Expr(box expr)               => {
+                self.solve_expr_locals(&mut expr.lhs);
+                self.solve_expr_locals(&mut expr.rhs);
+                return;
+            },
+            _                                 => return,
+        };
+
+        if !Ident::from(lbl.inner).is_local() {
+            return;
+        }
+
+        let val = match self.locals.get(&Ident::from(lbl.inner)) {
+            Some(def) if sized => self.size_from(def.pos),
+            Some(def)          => def.pos as i32,
+            None               => {
+                let res: Result<()> = undefined_label(lbl.inner.into(), lbl.span());
+                self.report(res);
+                0
+            },
+        };
+        *lit = Lit::Num(ast::Spanned::new(val, lit.span()));
+    }
+
+    /// Writes the value of every expression now that all labels are placed. Expressions inside of
+    /// `&` become entries of the constant pool, just like numbers.
+    fn solve_exprs(&mut self) {
+        for ExprUse { expr, ref_lvl, at } in std::mem::take(&mut self.exprs) {
+            let res = self.eval(&expr, &|lit| {
+                let (lbl, sized) = match lit {
+                    ast::Lit::Lbl(lbl)                          => (lbl, false),
+                    ast::Lit::SizeOf(box ast::Lit::Lbl(lbl), _) => (lbl, true),
+                    _                                           =>
+                        return error!("only numbers, characters, labels and constants can be used in expressions",
+                                      lit.span()),
+                };
+
+                let pos = self.label_pos(Ident::from(lbl.inner), lbl.span())?;
+                Ok(if sized { self.size_from(pos) } else { pos as i32 })
+            });
+            let val = match self.report(res) {
+                Some(val) => val,
+                None      => continue,
+            };
+
+            if ref_lvl == 0 {
+                // Uses that did not fit in the tape were already reported.
+                if let Some(cell) = self.tape.get_mut(at.pos) {
+                    *cell = val;
+                }
+            } else {
+                self.lit_uses.entry(Auto::Num(val, ref_lvl)).or_default().push(at);
+            }
+        }
+    }
+
+    /// Where a global label is, or a local label qualified with its global label, like
+    /// `print_str.arg`. Local labels are only kept in the debug info once their scope ends, so
+    /// this is only complete once every label is placed.
+    fn label_pos(&self, ident: Ident<'a>, span: Span<'a>) -> Result<Position> {
+        if let Some(def) = self.labels.get(&ident) {
+            return Ok(def.pos);
+        }
+        if ident.is_local() || !ident.0.contains('.') {
+            return undefined_label(ident, span);
+        }
+
+        let name = self.global_name(ident);
+        match self.info.lookup(&name, None) {
+            Ok(pos)                     => Ok(pos),
+            Err(LookupError::Missing)   => undefined_label(ident, span),
+            Err(LookupError::Ambiguous) => error!(format!("`{}` names more than one label", name), span),
+        }
+    }
+
+    fn constant(&self, name: &ast::Str<'a>) -> Result<i32> {
+        match self.constants.get(name.inner) {
+            Some(&value) => Ok(value),
+            // Parameters are replaced when a macro is expanded, so this can't be one.
+            None         => error!(format!("`{0}` is not a constant, did you mean '{0}?", name.inner), name.span()),
+        }
+    }
+
+    fn define_macro(&mut self, def: &ast::Macro<'a>) -> Result<()> {
+        use std::collections::hash_map::Entry::*;
+
+        if ast::Op::from_name(def.name.inner).is_some() {
+            return error!(format!("`{}` is an instruction and cannot be redefined", def.name), def.name.span());
+        }
+
+        match self.macros.entry(def.name.inner) {
+            Occupied(_)   => error!("macro defined twice", def.name.span()),
+            Vacant(entry) => {
+                entry.insert(def.clone());
+                Ok(())
+            },
+        }
+    }
+
+    fn expand_macro(&mut self, call: &ast::Call<'a>) -> Result<usize> {
+        // `$` in an argument is where the macro is used, not wherever the parameter ends up.
+        let call = &ast::Call { name: call.name, args: self.resolve_args(&call.args)?, span: call.span.clone() };
+        let def = match self.macros.get(call.name) {
+            Some(def) => def.clone(),
+            // A name alone in a line looks like an instruction, but it may be a constant used as data.
+            None if call.args.is_empty() && self.constants.contains_key(call.name) => {
+                let name = ast::Lit::Name(ast::Spanned::new(call.name, call.span.clone()));
+                return self.assemble_stmt(&ast::Stmt::Lit(name));
+            },
+            None      => return error!(format!("no instruction or macro named `{}`", call.name), call.span.clone()),
+        };
+
+        if self.depth >= MAX_MACRO_DEPTH {
+            return error!(format!("macro `{}` expands too deep, is it recursive?", call.name), call.span.clone());
+        }
+
+        self.macro_count += 1;
+        let body = macros::expand(&def, call, self.macro_count)?;
+        let body = self.select(&body);
+
+        // Repetitions also count towards the depth, so the outermost call is the one that finds
+        // no call site yet.
+        let outermost = self.call_site.is_none();
+        if outermost {
+            self.call_site = Some(call.span.clone());
+        }
+        self.depth += 1;
+        let count = self.assemble_stmts(&body);
+        self.depth -= 1;
+        if outermost {
+            self.call_site = None;
+        }
+
+        Ok(count)
+    }
+
+    /// Assembles the body of a repetition once for every time or value. Unlike with macros, the
+    /// cells belong to the lines of the body.
+    fn repeat(&mut self, rept: &ast::Rept<'a>) -> Result<usize> {
+        use ast::{ Arg, Lit, ReptKind };
+
+        let values: Vec<Option<(&'a str, Arg<'a>)>> = match &rept.kind {
+            ReptKind::Count(count)       => {
+                let times = self.const_value(&self.resolve(count)?)?;
+                if times < 0 {
+                    return error!(format!("cannot repeat a block {} times", times), count.span());
+                }
+                if times > MAX_REPEAT {
+                    return error!(format!("cannot repeat a block {} times, the most is {}", times, MAX_REPEAT),
+                                  rept.span.clone());
+                }
+                vec![None; times as usize]
+            },
+            // `$` in a value is where the repetition is, just like in the arguments of a macro.
+            ReptKind::Irp(param, values) => values
+                .iter()
+                .map(|value| Ok(Some((param.inner, Arg::Lit(self.resolve(value)?)))))
+                .collect::<Result<_>>()?,
+            ReptKind::Irpc(param, chars) => parser::unescape(chars.inner)
+                .map_err(|msg| Error::new(msg, chars.span()))?
+                .into_iter()
+                .map(|c| Some((param.inner, Arg::Lit(Lit::Chr(ast::Spanned::new(c, chars.span()))))))
+                .collect(),
+        };
+
+        let mut count = 0;
+        for value in values {
+            self.macro_count += 1;
+            let body = macros::repeat(&rept.body, value.as_ref().map(|(param, arg)| (*param, arg)), self.macro_count)?;
+            let body = self.select(&body);
+
+            self.depth += 1;
+            count += self.assemble_stmts(&body);
+            self.depth -= 1;
+        }
+        Ok(count)
+    }
+
     fn assemble_inst(&mut self, inst: &ast::Inst<'a>) -> Result<usize> {
         use ast::Op::*;
         use ast::{ Arg, Lit };
@@ -497,6 +1769,7 @@ impl<'a> Assembler<'a> {
                 let mut arg_vals = [0; 3];
 
                 let mut desugared_inst = inst.clone();
+                let mut deref_lbls = Vec::new();
 
                 // First resolve all of the derefs
                 for (i, arg) in inst.args.iter().enumerate() {
@@ -508,17 +1781,21 @@ impl<'a> Assembler<'a> {
                                 let macro_lbl = self.unique_lbl(".__deref_arg", span);
                                 let gen = self.assemble_deref_arg(&deref, &macro_lbl)?;
 
-                                let pos = self.get_pos();
-                                self.add_local_lbl_to(&macro_lbl, pos + i + 1)?;
-
                                 count += gen;
-                                desugared_inst.args[i] = ast::Arg::Lbl(macro_lbl);
+                                desugared_inst.args[i] = ast::Arg::Lbl(macro_lbl.clone());
+                                deref_lbls.push((i, macro_lbl));
                             }
                         },
                         _ => (),
                     }
                 }
 
+                // The instruction only starts after the code of every deref.
+                let pos = self.get_pos();
+                for (i, macro_lbl) in deref_lbls {
+                    self.add_local_lbl_to(&macro_lbl, pos + i + 1, LabelKind::Auto)?;
+                }
+
                 // Now get arg values.
                 for (i, arg) in inst.args.iter().enumerate() {
                     arg_vals[i] = self.assemble_arg(arg, i)?;
@@ -531,9 +1808,14 @@ impl<'a> Assembler<'a> {
                     println!("{}", desugared_inst);
                 }
 
-                self.push_tape(inst.op as i32);
+                self.info.insts.insert(self.get_pos(), InstInfo {
+                    op: inst.op,
+                    span: self.call_site.clone().unwrap_or_else(|| inst.span.clone()),
+                    scope: self.scope,
+                });
+                self.push_tape(inst.op as i32, CellKind::Opcode, inst.span.clone());
                 for i in 0..inst.args.len() {
-                    self.push_tape(arg_vals[i]);
+                    self.push_tape(arg_vals[i], CellKind::Arg, inst.args[i].span());
                 }
                 count += 1 + inst.args.len();
             },
@@ -545,7 +1827,7 @@ impl<'a> Assembler<'a> {
                 count += self.assemble_stmts(stmts! { inst.span.clone() =>
                     [Cpy arg (% @* [sp])]
                     [Add (% [sp]) (% @& (# -1)) (% [sp])]
-                })?;
+                });
             },
             Pop => {
                 let arg = inst.args[0].clone();
@@ -553,7 +1835,7 @@ impl<'a> Assembler<'a> {
                 count += self.assemble_stmts(stmts! { inst.span.clone() => 
                     [Add (% [sp]) (% @& (# 1)) (% [sp])]
                     [Cpy (% @* [sp]) arg]
-                })?;
+                });
             },
             Cal => {
                 let jmp_back = self.unique_lbl(".__ret", inst.span.clone());
@@ -564,7 +1846,7 @@ impl<'a> Assembler<'a> {
                     [Psh (% @& jmp_back.clone())]
                     [Jmp procedure_lbl]
                     [label jmp_back]
-                })?;
+                });
             },
             Ret => {
                 let tmp = ("__tmp", 0);
@@ -572,7 +1854,7 @@ impl<'a> Assembler<'a> {
                 count += self.assemble_stmts(stmts! { inst.span.clone() => 
                     [Pop (% [tmp])]
                     [Jmp (% [tmp])]
-                })?;
+                });
             },
         }
         Ok(count)
@@ -588,10 +1870,20 @@ impl<'a> Assembler<'a> {
                 error!("cannot dereference this type", span.clone()),
 
             Lit::Lbl(_) |
-            Lit::Deref(_) =>
-                self.assemble_stmts(stmts! { lit.span() =>
+            Lit::Deref(_) |
+            Lit::Expr(_) |
+            Lit::SizeOf(..) =>
+                Ok(self.assemble_stmts(stmts! { lit.span() =>
                     [Cpy (% lit.clone()) (% [macro_lbl.inner])]
-                }),
+                })),
+
+            Lit::Name(name) => {
+                self.constant(name)?;
+                error!("cannot dereference a constant", name.span())
+            },
+
+            Lit::Here(_) =>
+                unreachable!("`$` is replaced before assembling"),
 
             Lit::Ref(_) =>
                 unreachable!("In this case it means that there would be a *&. \
@@ -605,7 +1897,7 @@ impl<'a> Assembler<'a> {
         let arg_pos = self.get_pos() + arg_idx + 1;
         match arg {
             Arg::Lbl(lbl) => {
-                self.add_local_lbl_to(lbl, arg_pos)?;
+                self.add_local_lbl_to(lbl, arg_pos, LabelKind::Arg)?;
                 Ok(arg_pos as i32)
             },
             Arg::Lit(lit) => {
@@ -616,6 +1908,13 @@ impl<'a> Assembler<'a> {
                     Lit::Ref(box r) => self.get_value(r, 1, arg_pos).map(|v| v as i32),
                     Lit::Str(s)     => error!("string literal in argument position is not allowed", s.span()),
                     Lit::Deref(_)   => Ok(EMPTY_DEFAULT),
+                    Lit::Name(name) => self.constant(name),
+                    Lit::Expr(_) |
+                    Lit::SizeOf(..) => {
+                        self.use_expr(lit, 0, arg_pos);
+                        Ok(0)
+                    },
+                    Lit::Here(_)    => unreachable!("`$` is replaced before assembling"),
                 }
             },
         }
@@ -626,28 +1925,40 @@ impl<'a> Assembler<'a> {
 
         match lit {
             Lit::Num(num)     => {
-                self.push_tape(**num);
+                self.push_tape(**num, CellKind::Lit, num.span());
                 Ok(1)
             },
             Lit::Chr(chr)     => {
-                self.push_tape(**chr as i32);
+                self.push_tape(**chr as i32, CellKind::Lit, chr.span());
                 Ok(1)
             },
             Lit::Str(s)       => {
-                self.push_string(s.inner, s.span.clone())
+                self.push_string(s.inner, s.span.clone(), CellKind::Lit)
             },
             // TODO: Remove this requirement.
             Lit::Lbl(lbl)     => {
                 let val = self.get_label(lbl, self.get_pos()) as i32;
-                self.push_tape(val);
+                self.push_tape(val, CellKind::Lit, lbl.span());
                 Ok(1)
             },
             Lit::Ref(box r)   => {
                 let val = self.get_value(r, 1, self.get_pos())? as i32;
-                self.push_tape(val);
+                self.push_tape(val, CellKind::Lit, lit.span());
+                Ok(1)
+            },
+            Lit::Deref(box d) => error!("derefs not allowed here", d.span()),
+            Lit::Name(name)   => {
+                let val = self.constant(name)?;
+                self.push_tape(val, CellKind::Lit, name.span());
+                Ok(1)
+            },
+            Lit::Expr(_) |
+            Lit::SizeOf(..)   => {
+                self.use_expr(lit, 0, self.get_pos());
+                self.push_tape(0, CellKind::Lit, lit.span());
                 Ok(1)
             },
-            Lit::Deref(box d) => return error!("derefs not allowed here", d.span())
+            Lit::Here(_)      => unreachable!("`$` is replaced before assembling"),
         }
     }
 
@@ -675,6 +1986,7 @@ impl<'a> Assembler<'a> {
                         .push(LabelRef::new(use_pos, s.span()));
                     },
                 Lit::Lbl(lbl)   => {
+                    self.mark_used(Ident::from(lbl.inner));
                     break self.lit_uses
                         .entry(Auto::Lbl(Ident::from(lbl.inner), ref_lvl))
                         .or_default()
@@ -687,6 +1999,16 @@ impl<'a> Assembler<'a> {
                 Lit::Deref(d)   => {
                     return error!("derefs are not allowed here", d.span())
                 },
+                Lit::Name(name) => {
+                    let val = self.constant(name)?;
+                    break self.lit_uses
+                        .entry(Auto::Num(val, ref_lvl))
+                        .or_default()
+                        .push(LabelRef::new(use_pos, name.span()));
+                    },
+                Lit::Expr(_) |
+                Lit::SizeOf(..) => break self.use_expr(lit, ref_lvl, use_pos),
+                Lit::Here(_)    => unreachable!("`$` is replaced before assembling"),
             }
         }
         Ok(0)
@@ -696,7 +2018,9 @@ impl<'a> Assembler<'a> {
         let labels = if Ident::from(lbl.inner).is_local() { &mut self.locals } else { &mut self.labels };
 
         let ident = Ident::from(lbl.inner);
-        if let Some(def) = labels.get(&ident).cloned() {
+        let def = labels.get(&ident).cloned();
+        self.mark_used(ident);
+        if let Some(def) = def {
             def.pos
         } else {
             let lbl_ref = LabelRef::new(pos, lbl.span());
@@ -711,16 +2035,29 @@ impl<'a> Assembler<'a> {
 
     #[inline]
     fn add_local_lbl(&mut self, lbl: &ast::Label<'a>) -> Result<()> {
-        self.add_local_lbl_to(lbl, self.get_pos())
+        // Labels with an id were generated by the assembler.
+        let kind = if lbl.1 > 0 { LabelKind::Auto } else { LabelKind::Local };
+        self.add_local_lbl_to(lbl, self.get_pos(), kind)
     }
 
-    fn add_local_lbl_to(&mut self, lbl: &ast::Label<'a>, to: usize) -> Result<()> {
+    fn add_local_lbl_to(&mut self, lbl: &ast::Label<'a>, to: usize, kind: LabelKind) -> Result<()> {
         use std::collections::hash_map::Entry::*;
 
-        match self.locals.entry(Ident::from(lbl.inner)) {
+        let ident = Ident::from(lbl.inner);
+        // Numeric labels are named like other local labels, e.g. `main.1`.
+        let (name, id) = if ident.is_numeric() { (format!(".{}", ident.0), ident.1) } else { (ident.to_string(), 0) };
+        match self.locals.entry(ident) {
             Occupied(_)   => error!("label defined twice", lbl.span.clone()),
             Vacant(entry) => {
                 entry.insert(LabelDef::new(to, lbl.span.clone()));
+                self.info.labels.push(LabelInfo {
+                    name,
+                    scope: self.scope,
+                    id,
+                    pos: to,
+                    kind,
+                    span: Some(lbl.span()),
+                });
                 Ok(())
             },
         }
@@ -735,6 +2072,15 @@ impl<'a> Assembler<'a> {
             Occupied(_)   => error!("label defined twice", lbl.span.clone()),
             Vacant(entry) => {
                 entry.insert(LabelDef::new(curr_pos, lbl.span.clone()));
+                self.info.labels.push(LabelInfo {
+                    name: self.global_name(Ident::from(lbl.inner)),
+                    scope: None,
+                    id: 0,
+                    pos: curr_pos,
+                    kind: LabelKind::Global,
+                    span: Some(lbl.span()),
+                });
+                self.scope = Some(Scope { namespace: self.namespace.map(|(ns, _)| ns), name: lbl.0 });
                 self.solve_locals()
             },
         }
@@ -743,11 +2089,17 @@ impl<'a> Assembler<'a> {
     fn solve_locals(&mut self) -> Result<()> {
         use std::collections::btree_map::Entry::*;
 
+        let mut exprs = std::mem::take(&mut self.exprs);
+        for expr in &mut exprs {
+            self.solve_expr_locals(&mut expr.expr);
+        }
+        self.exprs = exprs;
+
         // Removes all uses of local labels in values and replaces them with uses of actual
         // numbers.
         for (lbl, def) in self.locals.drain() {
             let local_lbl_uses: Vec<_> = self.lit_uses
-                .drain_filter(|k, _| (Auto::Lbl(lbl, 0)..=Auto::Lbl(lbl, u32::MAX)).contains(k))
+                .extract_if(Auto::Lbl(lbl, 0)..=Auto::Lbl(lbl, u32::MAX), |_, _| true)
                 .collect();
 
             for (auto, uses) in local_lbl_uses {
@@ -758,6 +2110,21 @@ impl<'a> Assembler<'a> {
             }
         }
 
+        // The local labels still used were not defined in the scope, such as a `'1f` with no `1:`
+        // after it. They can't refer to a label of the next scope.
+        let undefined: Vec<_> = self.lit_uses
+            .extract_if(.., |auto, _| matches!(auto, Auto::Lbl(lbl, _) if lbl.is_local()))
+            .collect();
+        let mut prev_lbl = None;
+        for (auto, uses) in undefined {
+            let (lbl, _) = auto.unwrap_lbl();
+            if prev_lbl != Some(lbl) {
+                let res: Result<()> = undefined_label(lbl, uses[0].span.clone());
+                self.report(res);
+                prev_lbl = Some(lbl);
+            }
+        }
+
         Ok(())
     }
 
@@ -775,6 +2142,14 @@ impl<'a> Assembler<'a> {
             Vacant(entry)   => {
                 let end = self.tape.len();
                 entry.insert(LabelDef::auto(end));
+                self.info.labels.push(LabelInfo {
+                    name: "__end".to_string(),
+                    scope: None,
+                    id: 0,
+                    pos: end,
+                    kind: LabelKind::Auto,
+                    span: None,
+                });
                 Ok(())
             },
         }
@@ -791,3 +2166,75 @@ impl<'a> Assembler<'a> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The first `len` cells of the tape of `program`, or the messages of its errors.
+    fn assemble(program: &str, len: usize) -> std::result::Result<Vec<i32>, Vec<String>> {
+        let prog = parser::parse_asm(program, 0).map_err(|_| vec!["parse error".to_string()])?;
+        let asm = Assembler::new(64, false, Lints::default(), Encoding::Utf8);
+        match asm.assemble(&prog.stmts) {
+            Ok(tape)    => Ok(tape[..len].to_vec()),
+            Err(errors) => Err(errors.iter().map(|e| e.format(&|_| String::new())).collect()),
+        }
+    }
+
+    fn assert_error(program: &str, message: &str) {
+        match assemble(program, 0) {
+            Ok(_)       => panic!("`{}` assembled", program),
+            Err(errors) => assert!(errors.iter().any(|e| e.contains(message)), "{:?}", errors),
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(assemble(".word 1 + 2, 7 - 10, 6 * -7, 7 / 2, -7 / 2\n", 5), Ok(vec![3, -3, -42, 3, -3]));
+        assert_eq!(assemble(".word 2 + 3 * 4, (2 + 3) * 4\n", 2), Ok(vec![14, 20]));
+        assert_eq!(assemble(".equ N 5\n.word N * N, 'a' + 1\n", 2), Ok(vec![25, 98]));
+    }
+
+    #[test]
+    fn comparisons() {
+        let conds = ["1 == 1", "1 != 1", "1 < 2", "2 <= 1", "2 > 1", "1 >= 2", "-1 < 0"];
+        let program: String = conds
+            .iter()
+            .map(|cond| format!(".if {}\n.word 1\n.else\n.word 0\n.endif\n", cond))
+            .collect();
+        assert_eq!(assemble(&program, 7), Ok(vec![1, 0, 1, 0, 1, 0, 1]));
+    }
+
+    #[test]
+    fn here() {
+        // `$` is where the first cell of its instruction goes, or the cell of its value in data.
+        assert_eq!(assemble(".word 9, 9\n.word $, $ + 1\n", 4), Ok(vec![9, 9, 2, 4]));
+        assert_eq!(assemble("jmp &$\n", 3), Ok(vec![5, 2, 0]));
+    }
+
+    #[test]
+    fn size_of() {
+        let program = "\
+            data: .word 1, 2, 3\n\
+            next: .word sizeof('data), sizeof(\"abc\\n\"), sizeof('next) * 2\n";
+        assert_eq!(assemble(program, 6), Ok(vec![1, 2, 3, 3, 4, 6]));
+        assert_eq!(assemble("lbl: .word 'lbl + 1, 'end - 'lbl\nend:\n", 2), Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_error(".word 1 / 0\n", "division by zero");
+        assert_error(".equ Z 0\n.word 1 / Z\n", "division by zero");
+        assert_error(".word 1 / (2 - 2)\n", "division by zero");
+    }
+
+    #[test]
+    fn overflow() {
+        let message = "does not fit in a cell";
+        assert_error(".word 0x7FFFFFFF + 1\n", message);
+        assert_error(".word -0x80000000 - 1\n", message);
+        assert_error(".word 0x10000 * 0x10000\n", message);
+        assert_error(".word -0x80000000 / -1\n", message);
+        assert_eq!(assemble(".word 0x7FFFFFFF + -1, -0x80000000 / 1\n", 2), Ok(vec![i32::MAX - 1, i32::MIN]));
+    }
+}
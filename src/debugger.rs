@@ -0,0 +1,254 @@
+use std::collections::BTreeSet;
+use std::io::{ BufRead, Write };
+
+use crate::codegen::{ DebugInfo, LookupError, Scope };
+use crate::encoding::Encoding;
+use crate::machine::{ Machine, State };
+
+const HELP: &str = "\
+commands:
+    b, break <loc>     set a breakpoint at a label (main, .loop, main.loop) or address
+    d, delete <loc>    remove a breakpoint
+    breakpoints        list all breakpoints
+    s, step [n]        execute n instructions (default 1)
+    c, continue        run until a breakpoint or hlt is reached
+    p, print <expr>    print a cell: 'sp, *'ptr, &'sp, 42
+    l, list            show the current instruction
+    r, restart         reload the tape and start over
+    q, quit            exit the debugger";
+
+type Result<T> = std::result::Result<T, String>;
+
+/// An interactive debugger for an assembled program.
+pub struct Debugger<'a> {
+    initial: Vec<i32>,
+    machine: Machine,
+    info: DebugInfo<'a>,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(tape: Vec<i32>, info: DebugInfo<'a>, encoding: Encoding) -> Debugger<'a> {
+        Debugger {
+            machine: Machine::new(tape.clone(), encoding),
+            initial: tape,
+            info,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn repl(&mut self) -> std::io::Result<()> {
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+
+        self.list();
+        loop {
+            print!("(tdb) ");
+            std::io::stdout().flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None       => break,
+            };
+
+            let mut words = line.split_whitespace();
+            let cmd = match words.next() {
+                Some(cmd) => cmd,
+                None      => continue,
+            };
+            let arg = words.collect::<Vec<_>>().join(" ");
+
+            let res = match cmd {
+                "b" | "break"    => self.add_breakpoint(&arg),
+                "d" | "delete"   => self.remove_breakpoint(&arg),
+                "breakpoints"    => {
+                    self.list_breakpoints();
+                    Ok(())
+                },
+                "s" | "step"     => self.step(&arg),
+                "c" | "continue" => {
+                    self.cont();
+                    Ok(())
+                },
+                "p" | "print"    => self.print(&arg),
+                "l" | "list"     => {
+                    self.list();
+                    Ok(())
+                },
+                "r" | "restart"  => {
+                    self.machine = Machine::new(self.initial.clone(), self.machine.encoding());
+                    self.list();
+                    Ok(())
+                },
+                "h" | "help"     => {
+                    println!("{}", HELP);
+                    Ok(())
+                },
+                "q" | "quit"     => break,
+                _                => Err(format!("unknown command `{}`, try `help`", cmd)),
+            };
+
+            if let Err(e) = res {
+                println!("error: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn scope(&self) -> Option<Scope<'a>> {
+        self.info.insts
+            .range(..=self.machine.ip())
+            .next_back()
+            .and_then(|(_, inst)| inst.scope)
+    }
+
+    /// Resolves a location given either as a label or as an address.
+    fn location(&self, loc: &str) -> Result<usize> {
+        let loc = loc.trim();
+        if loc.is_empty() {
+            return Err("expected a label or an address".to_string());
+        }
+
+        if let Ok(addr) = loc.parse() {
+            return Ok(addr);
+        }
+
+        let name = loc.strip_prefix('\'').unwrap_or(loc);
+        self.info
+            .lookup(name, self.scope())
+            .map_err(|err| match err {
+                LookupError::Missing   => format!("no label named `{}`", name),
+                LookupError::Ambiguous => format!("`{}` names more than one label", name),
+            })
+    }
+
+    fn add_breakpoint(&mut self, loc: &str) -> Result<()> {
+        let addr = self.location(loc)?;
+        if !self.info.insts.contains_key(&addr) {
+            println!("warning: there is no instruction at address {}", addr);
+        }
+        self.breakpoints.insert(addr);
+        println!("breakpoint at {}", self.describe(addr));
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, loc: &str) -> Result<()> {
+        let addr = self.location(loc)?;
+        if self.breakpoints.remove(&addr) {
+            Ok(())
+        } else {
+            Err(format!("there is no breakpoint at address {}", addr))
+        }
+    }
+
+    fn list_breakpoints(&self) {
+        for &addr in &self.breakpoints {
+            println!("{}", self.describe(addr));
+        }
+    }
+
+    fn step(&mut self, arg: &str) -> Result<()> {
+        let n = if arg.is_empty() {
+            1
+        } else {
+            arg.parse().map_err(|e| format!("invalid step count: {}", e))?
+        };
+
+        for _ in 0..n {
+            if !self.exec_one() {
+                break;
+            }
+        }
+        self.list();
+        Ok(())
+    }
+
+    fn cont(&mut self) {
+        while self.exec_one() {
+            if self.breakpoints.contains(&self.machine.ip()) {
+                break;
+            }
+        }
+        self.list();
+    }
+
+    /// Executes one instruction and tells if the machine can keep going.
+    fn exec_one(&mut self) -> bool {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        let res = self.machine.step(&mut out);
+        let _ = out.flush();
+
+        match res {
+            Ok(State::Running) => true,
+            Ok(State::Halted)  => {
+                println!("program halted after {} steps", self.machine.steps());
+                false
+            },
+            Err(e)             => {
+                println!("runtime error: {}", e);
+                false
+            },
+        }
+    }
+
+    /// Evaluates expressions like `'sp`, `*'ptr`, `&'sp` or `42`.
+    fn print(&self, expr: &str) -> Result<()> {
+        let expr = expr.trim();
+        let derefs = expr.chars().take_while(|&c| c == '*').count();
+        let rest = &expr[derefs..];
+        let (is_ref, loc) = match rest.strip_prefix('&') {
+            Some(loc) => (true, loc),
+            None      => (false, rest),
+        };
+
+        let addr = self.location(loc)? as i32;
+        let mut val = if is_ref {
+            addr
+        } else {
+            self.machine.read(addr).map_err(|e| e.to_string())?
+        };
+
+        for _ in 0..derefs {
+            val = self.machine.read(val).map_err(|e| e.to_string())?;
+        }
+
+        println!("{} = {}", expr, val);
+        Ok(())
+    }
+
+    /// The address followed by the labels defined at it.
+    fn describe(&self, addr: usize) -> String {
+        let mut desc = addr.to_string();
+        for lbl in self.info.labels_at(addr) {
+            desc.push_str(&format!(" <{}>", lbl.qualified_name()));
+        }
+        desc
+    }
+
+    /// Shows the current instruction disassembled, along with the source line that generated it.
+    fn list(&self) {
+        let ip = self.machine.ip();
+        let marker = if self.machine.state() == State::Halted { "halted" } else { "=>" };
+
+        match self.machine.decode(ip) {
+            Some((op, args)) => {
+                print!("{} {}: {}", marker, self.describe(ip), op);
+                for &arg in args {
+                    print!(" {}", arg);
+                    if let Some(lbl) = self.info.labels.iter().find(|lbl| lbl.pos as i32 == arg) {
+                        print!(" <{}>", lbl.qualified_name());
+                    }
+                }
+                println!();
+            },
+            None => println!("{} {}: <not an instruction>", marker, self.describe(ip)),
+        }
+
+        if let Some(inst) = self.info.insts.get(&ip) {
+            let (line, _) = inst.span.start_pos().line_col();
+            println!("{:>5} | {}", line, inst.span.start_pos().line_of().trim_end());
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::Write;
+
+/// How text is stored in the cells of the tape, which is used both when assembling strings and
+/// characters and when `put` prints a cell.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Encoding {
+    // One byte of UTF-8 per cell.
+    #[default]
+    Utf8,
+    // One code point per cell.
+    Utf32,
+}
+
+impl Encoding {
+    pub const NAMES: [&'static str; 2] = ["utf8", "utf32"];
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "utf8"  => Some(Encoding::Utf8),
+            "utf32" => Some(Encoding::Utf32),
+            _       => None,
+        }
+    }
+
+    /// The cells of a string.
+    pub fn encode_str(self, chars: &[char]) -> Vec<i32> {
+        match self {
+            Encoding::Utf8  => chars.iter().collect::<String>().bytes().map(i32::from).collect(),
+            Encoding::Utf32 => chars.iter().map(|&c| c as i32).collect(),
+        }
+    }
+
+    /// Writes what `put` prints for a cell, failing if it is not a character.
+    pub fn put<W: Write>(self, out: &mut W, cell: i32) -> std::io::Result<bool> {
+        match self {
+            Encoding::Utf8  => out.write_all(&[cell as u8])?,
+            Encoding::Utf32 => match char::from_u32(cell as u32) {
+                Some(c) => write!(out, "{}", c)?,
+                None    => return Ok(false),
+            },
+        }
+        Ok(true)
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Encoding::Utf8  => "utf8",
+            Encoding::Utf32 => "utf32",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{ HashMap, HashSet };
+
+
+use crate::ast::{ Arg, Lit, Op, Span, Stmt };
+use crate::parser::Error;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Lint {
+    UnusedLabel,
+    JmpWithoutRef,
+    OrgOverlap,
+    FallThrough,
+    UnreferencedData,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UnusedLabel,
+        Lint::JmpWithoutRef,
+        Lint::OrgOverlap,
+        Lint::FallThrough,
+        Lint::UnreferencedData,
+    ];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lint::UnusedLabel      => "W001",
+            Lint::JmpWithoutRef    => "W002",
+            Lint::OrgOverlap       => "W003",
+            Lint::FallThrough      => "W004",
+            Lint::UnreferencedData => "W005",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedLabel      => "unused-label",
+            Lint::JmpWithoutRef    => "jmp-without-ref",
+            Lint::OrgOverlap       => "org-overlap",
+            Lint::FallThrough      => "fall-through",
+            Lint::UnreferencedData => "unreferenced-data",
+        }
+    }
+
+    pub fn default_level(&self) -> Level {
+        match self {
+            // Overwriting cells is almost never intended and yields a broken tape.
+            Lint::OrgOverlap => Level::Deny,
+            _                => Level::Warn,
+        }
+    }
+
+    /// Finds a lint either by its name or by its code.
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL
+            .iter()
+            .copied()
+            .find(|lint| lint.name() == name || lint.code().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// Which lints are enabled and whether they are reported as warnings or as errors.
+#[derive(Debug, Clone, Default)]
+pub struct Lints {
+    levels: HashMap<Lint, Level>,
+    // Files that are never linted, like the standard library.
+    ignored: HashSet<usize>,
+}
+
+impl Lints {
+    pub fn allow_all() -> Lints {
+        Lints {
+            levels: Lint::ALL.iter().map(|&lint| (lint, Level::Allow)).collect(),
+            ignored: HashSet::new(),
+        }
+    }
+
+    /// Stops reporting lints in `file`.
+    pub fn ignore_file(&mut self, file: usize) {
+        self.ignored.insert(file);
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels.get(&lint).copied().unwrap_or_else(|| lint.default_level())
+    }
+
+    /// Sets the level of a lint by its name or code, or of every lint with `all`.
+    pub fn set(&mut self, name: &str, level: Level) -> Result<(), String> {
+        if name == "all" {
+            for &lint in &Lint::ALL {
+                self.levels.insert(lint, level);
+            }
+        } else {
+            let lint = Lint::from_name(name).ok_or_else(|| format!("unknown lint `{}`", name))?;
+            self.levels.insert(lint, level);
+        }
+        Ok(())
+    }
+
+    /// Builds the diagnostic for a lint, or `None` if it is allowed.
+    pub fn diagnostic<'a>(&self, lint: Lint, msg: &str, span: Span<'a>) -> Option<(Level, Error)> {
+        self.diagnostic_with_note(lint, msg, span, None)
+    }
+
+    /// Like `diagnostic`, but also points at a second, related span.
+    pub fn diagnostic_with_note<'a>(&self, lint: Lint, msg: &str, span: Span<'a>, note: Option<(&str, Span<'a>)>)
+        -> Option<(Level, Error)>
+    {
+        if self.ignored.contains(&span.file) {
+            return None;
+        }
+
+        let level = self.level(lint);
+        let severity = match level {
+            Level::Allow => return None,
+            Level::Warn  => "warning",
+            Level::Deny  => "error",
+        };
+
+        let message = format!("{}[{}]: {} (`{}`)", severity, lint.code(), msg, lint.name());
+        let mut err = Error::new(message, span);
+        if let Some((note, note_span)) = note {
+            err = err.with_note(Error::new(format!("note: {}", note), note_span));
+        }
+        Some((level, err))
+    }
+}
+
+/// Checks that only need to look at the statements as they were written.
+pub fn check_stmts<'a, F>(stmts: &[Stmt<'a>], mut report: F)
+where
+    F: FnMut(Lint, &str, Span<'a>),
+{
+    let final_hlt = stmts
+        .iter()
+        .rposition(|stmt| matches!(stmt, Stmt::Inst(inst) if inst.op == Op::Hlt));
+
+    // The last statement that emitted something, if it was an instruction that may go on to the
+    // next cell.
+    let mut open_inst = None;
+    // Whether there was a label since the final `hlt`.
+    let mut labeled = false;
+
+    for (i, stmt) in stmts.iter().enumerate() {
+        match stmt {
+            Stmt::Inst(inst) => {
+                if inst.op == Op::Jmp {
+                    if let Arg::Lit(Lit::Lbl(lbl)) = &inst.args[0] {
+                        report(Lint::JmpWithoutRef,
+                            &format!("jumps to the address stored in '{0}, use &'{0} to jump to '{0} itself", lbl.0),
+                            inst.span.clone());
+                    }
+                }
+
+                open_inst = match inst.op {
+                    Op::Hlt | Op::Jmp | Op::Ret => None,
+                    _                           => Some(inst.span.clone()),
+                };
+            },
+            Stmt::Lit(_) | Stmt::Fill(_) | Stmt::Word(_) | Stmt::Incbin(_) => {
+                if let Some(span) = open_inst.take() {
+                    report(Lint::FallThrough, "execution falls through into data, missing `hlt` or `jmp`", span);
+                }
+
+                let span = match stmt {
+                    Stmt::Lit(lit)   => lit.span(),
+                    Stmt::Fill(fill) => fill.span.clone(),
+                    Stmt::Word(lits) => lits[0].span(),
+                    Stmt::Incbin(b)  => b.span.clone(),
+                    _                => unreachable!(),
+                };
+                if final_hlt.is_some_and(|hlt| i > hlt) && !labeled {
+                    report(Lint::UnreferencedData, "data after the final `hlt` is never referenced", span);
+                }
+            },
+            Stmt::Label(_) => {
+                if final_hlt.is_some_and(|hlt| i > hlt) {
+                    labeled = true;
+                }
+            },
+            Stmt::Org(_) => open_inst = None,
+            // What a macro or repetition expands to is not known here.
+            Stmt::Call(_) | Stmt::Rept(_) => open_inst = None,
+            Stmt::Macro(_) | Stmt::Include(_) | Stmt::Equ(_) | Stmt::Cond(..) | Stmt::Namespace(..) | Stmt::Global(_) => (),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::io::Write;
+
+use crate::ast::Op;
+use crate::encoding::Encoding;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidOpcode { ip: usize, opcode: i32 },
+    OutOfBounds { ip: usize, addr: i32 },
+    InvalidChar { ip: usize, value: i32 },
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidOpcode { ip, opcode } =>
+                write!(f, "invalid opcode {} at address {}", opcode, ip),
+            Error::OutOfBounds { ip, addr } =>
+                write!(f, "address {} is out of the tape (instruction at address {})", addr, ip),
+            Error::InvalidChar { ip, value } =>
+                write!(f, "cannot print {}, which is not a unicode character (instruction at address {})", value, ip),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error { Error::Io(e) }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum State {
+    Running,
+    Halted,
+}
+
+/// Executes a tape produced by the `Assembler`.
+pub struct Machine {
+    tape: Vec<i32>,
+    ip: usize,
+    steps: usize,
+    state: State,
+    encoding: Encoding,
+}
+
+impl Machine {
+    pub fn new(tape: Vec<i32>, encoding: Encoding) -> Machine {
+        Machine {
+            tape,
+            ip: 0,
+            steps: 0,
+            state: State::Running,
+            encoding,
+        }
+    }
+
+    pub fn tape(&self) -> &[i32] {
+        &self.tape
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Reads the cell at `addr`, failing if it is outside of the tape.
+    pub fn read(&self, addr: i32) -> Result<i32> {
+        self.cell(addr).map(|i| self.tape[i])
+    }
+
+    fn cell(&self, addr: i32) -> Result<usize> {
+        if addr < 0 || addr as usize >= self.tape.len() {
+            Err(Error::OutOfBounds { ip: self.ip, addr })
+        } else {
+            Ok(addr as usize)
+        }
+    }
+
+    fn write(&mut self, addr: i32, val: i32) -> Result<()> {
+        let i = self.cell(addr)?;
+        self.tape[i] = val;
+        Ok(())
+    }
+
+    /// The argument `n` of the current instruction.
+    fn arg(&self, n: usize) -> Result<i32> {
+        self.read((self.ip + 1 + n) as i32)
+    }
+
+    /// The value of the cell pointed by the argument `n` of the current instruction.
+    fn arg_val(&self, n: usize) -> Result<i32> {
+        self.read(self.arg(n)?)
+    }
+
+    /// Decodes the instruction at `ip`.
+    pub fn fetch(&self) -> Result<Op> {
+        let opcode = self.read(self.ip as i32)?;
+        Op::from_opcode(opcode).ok_or(Error::InvalidOpcode { ip: self.ip, opcode })
+    }
+
+    /// Decodes the instruction at `addr` along with its arguments.
+    pub fn decode(&self, addr: usize) -> Option<(Op, &[i32])> {
+        let op = Op::from_opcode(*self.tape.get(addr)?)?;
+        let args = self.tape.get(addr + 1..addr + 1 + op.nargs())?;
+        Some((op, args))
+    }
+
+    /// Executes a single instruction, writing anything it prints to `out`.
+    pub fn step<W: Write>(&mut self, out: &mut W) -> Result<State> {
+        if self.state == State::Halted {
+            return Ok(State::Halted);
+        }
+
+        let op = self.fetch()?;
+        let mut next = self.ip + 1 + op.nargs();
+
+        match op {
+            Op::Hlt => {
+                self.state = State::Halted;
+                next = self.ip;
+            },
+            Op::Add => {
+                let val = self.arg_val(0)?.wrapping_add(self.arg_val(1)?);
+                self.write(self.arg(2)?, val)?;
+            },
+            Op::Mul => {
+                let val = self.arg_val(0)?.wrapping_mul(self.arg_val(1)?);
+                self.write(self.arg(2)?, val)?;
+            },
+            Op::Cle => {
+                let val = self.arg_val(0)? < self.arg_val(1)?;
+                self.write(self.arg(2)?, val as i32)?;
+            },
+            Op::Ceq => {
+                let val = self.arg_val(0)? == self.arg_val(1)?;
+                self.write(self.arg(2)?, val as i32)?;
+            },
+            Op::Jmp => {
+                next = self.cell(self.arg_val(0)?)?;
+            },
+            Op::Beq => {
+                if self.arg_val(0)? != 0 {
+                    next = self.cell(self.arg_val(1)?)?;
+                }
+            },
+            Op::Cpy => {
+                let val = self.arg_val(0)?;
+                self.write(self.arg(1)?, val)?;
+            },
+            Op::Put => {
+                let value = self.arg_val(0)?;
+                if !self.encoding.put(out, value)? {
+                    return Err(Error::InvalidChar { ip: self.ip, value });
+                }
+            },
+            Op::Ptn => {
+                write!(out, "{}", self.arg_val(0)?)?;
+            },
+            Op::Psh | Op::Pop | Op::Cal | Op::Ret =>
+                unreachable!("pseudo instructions have no opcode"),
+        }
+
+        self.ip = next;
+        self.steps += 1;
+        Ok(self.state)
+    }
+
+    /// Runs until a `hlt` is reached or `max_steps` instructions were executed.
+    pub fn run<W: Write>(&mut self, out: &mut W, max_steps: Option<usize>) -> Result<State> {
+        while self.state == State::Running {
+            if max_steps.is_some_and(|max| self.steps >= max) {
+                break;
+            }
+            self.step(out)?;
+        }
+        out.flush()?;
+        Ok(self.state)
+    }
+}
+
+/// Reads a tape in the format written by the compiler: one cell per line.
+pub fn parse_tape(s: &str) -> std::result::Result<Vec<i32>, String> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| line.trim().parse().map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(tape: Vec<i32>, encoding: Encoding) -> (Machine, Result<State>, Vec<u8>) {
+        let mut machine = Machine::new(tape, encoding);
+        let mut out = Vec::new();
+        let res = machine.run(&mut out, Some(100));
+        (machine, res, out)
+    }
+
+    fn run_ok(tape: Vec<i32>) -> Machine {
+        let (machine, res, _) = run(tape, Encoding::Utf8);
+        assert_eq!(res.unwrap(), State::Halted);
+        machine
+    }
+
+    #[test]
+    fn hlt() {
+        let machine = run_ok(vec![0]);
+        assert_eq!(machine.ip(), 0);
+        assert_eq!(machine.steps(), 1);
+    }
+
+    #[test]
+    fn add() {
+        let machine = run_ok(vec![1, 5, 6, 7, 0, 2, -5, 0]);
+        assert_eq!(machine.tape()[7], -3);
+
+        let machine = run_ok(vec![1, 5, 6, 7, 0, i32::MAX, 1, 0]);
+        assert_eq!(machine.tape()[7], i32::MIN);
+    }
+
+    #[test]
+    fn mul() {
+        let machine = run_ok(vec![2, 5, 6, 7, 0, 6, -7, 0]);
+        assert_eq!(machine.tape()[7], -42);
+    }
+
+    #[test]
+    fn cle() {
+        let machine = run_ok(vec![3, 5, 6, 7, 0, 1, 2, 9]);
+        assert_eq!(machine.tape()[7], 1);
+
+        let machine = run_ok(vec![3, 5, 6, 7, 0, 2, 2, 9]);
+        assert_eq!(machine.tape()[7], 0);
+    }
+
+    #[test]
+    fn ceq() {
+        let machine = run_ok(vec![4, 5, 6, 7, 0, 2, 2, 9]);
+        assert_eq!(machine.tape()[7], 1);
+
+        let machine = run_ok(vec![4, 5, 6, 7, 0, 1, 2, 9]);
+        assert_eq!(machine.tape()[7], 0);
+    }
+
+    #[test]
+    fn jmp() {
+        let machine = run_ok(vec![5, 3, 9, 4, 0]);
+        assert_eq!(machine.ip(), 4);
+        assert_eq!(machine.steps(), 2);
+    }
+
+    #[test]
+    fn beq() {
+        // Taken when the condition is not zero.
+        let machine = run_ok(vec![6, 5, 6, 9, 0, 1, 4]);
+        assert_eq!(machine.ip(), 4);
+
+        let machine = run_ok(vec![6, 5, 6, 0, 9, 0, 4]);
+        assert_eq!(machine.ip(), 3);
+    }
+
+    #[test]
+    fn cpy() {
+        let machine = run_ok(vec![7, 4, 5, 0, 42, 0]);
+        assert_eq!(machine.tape()[5], 42);
+    }
+
+    #[test]
+    fn put_utf8() {
+        // Each cell is one byte of UTF-8.
+        let (_, res, out) = run(vec![8, 5, 8, 6, 0, 0xC3, 0xA7], Encoding::Utf8);
+        assert_eq!(res.unwrap(), State::Halted);
+        assert_eq!(String::from_utf8(out).unwrap(), "ç");
+    }
+
+    #[test]
+    fn put_utf32() {
+        let (_, res, out) = run(vec![8, 3, 0, 'ç' as i32], Encoding::Utf32);
+        assert_eq!(res.unwrap(), State::Halted);
+        assert_eq!(String::from_utf8(out).unwrap(), "ç");
+
+        let (_, res, _) = run(vec![8, 3, 0, 0xD800], Encoding::Utf32);
+        assert!(matches!(res, Err(Error::InvalidChar { ip: 0, value: 0xD800 })));
+    }
+
+    #[test]
+    fn ptn() {
+        let (_, res, out) = run(vec![9, 3, 0, -12], Encoding::Utf8);
+        assert_eq!(res.unwrap(), State::Halted);
+        assert_eq!(out, b"-12");
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let (_, res, _) = run(vec![10], Encoding::Utf8);
+        assert!(matches!(res, Err(Error::InvalidOpcode { ip: 0, opcode: 10 })));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        // Reading through an argument.
+        let (_, res, _) = run(vec![7, 10, 0], Encoding::Utf8);
+        assert!(matches!(res, Err(Error::OutOfBounds { ip: 0, addr: 10 })));
+
+        // Writing.
+        let (_, res, _) = run(vec![7, 0, -1], Encoding::Utf8);
+        assert!(matches!(res, Err(Error::OutOfBounds { ip: 0, addr: -1 })));
+
+        // Jumping.
+        let (_, res, _) = run(vec![5, 2, 3], Encoding::Utf8);
+        assert!(matches!(res, Err(Error::OutOfBounds { ip: 0, addr: 3 })));
+
+        // Running off the end of the tape.
+        let (_, res, _) = run(vec![7, 0, 0], Encoding::Utf8);
+        assert!(matches!(res, Err(Error::OutOfBounds { ip: 3, addr: 3 })));
+    }
+
+    #[test]
+    fn max_steps() {
+        // An endless loop.
+        let mut machine = Machine::new(vec![5, 2, 0], Encoding::Utf8);
+        assert_eq!(machine.run(&mut Vec::new(), Some(5)).unwrap(), State::Running);
+        assert_eq!(machine.steps(), 5);
+
+        // It goes on from where it stopped.
+        assert_eq!(machine.run(&mut Vec::new(), Some(7)).unwrap(), State::Running);
+        assert_eq!(machine.steps(), 7);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{ HashMap, HashSet };
+
+use crate::ast::*;
+use crate::parser::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+macro_rules! error {
+    ($msg:expr, $span:expr) => {
+        Err(Error::new($msg.into(), $span))
+    };
+}
+
+/// Substitutes the parameters of a macro or of a repetition in its body.
+struct Expansion<'m, 'a> {
+    args: HashMap<&'a str, &'m Arg<'a>>,
+    // Local labels defined inside of the macro, which get a new id in each expansion.
+    locals: HashSet<&'a str>,
+    id: usize,
+    // Whether a value takes the place of its parameter, span included, so that its cells belong
+    // to the line of the body that uses it. Macros don't need it, their cells belong to the call.
+    at_use: bool,
+}
+
+/// Expands a use of `def`. Local labels defined by the macro are given `id`, so that they don't
+/// clash with the ones from other expansions, just like the labels generated for `cal`.
+pub fn expand<'a>(def: &Macro<'a>, call: &Call<'a>, id: usize) -> Result<Vec<Stmt<'a>>> {
+    if call.args.len() != def.params.len() {
+        return error!(format!("`{}` expects {} argument(s) but got {}", def.name, def.params.len(), call.args.len()),
+                      call.span.clone());
+    }
+
+    substitute(&def.body, def.params.iter().map(|p| p.inner).zip(&call.args).collect(), id, false)
+}
+
+/// Expands one repetition of `body`, in which `param` is replaced by its value for this
+/// repetition. Local labels are given `id`, just like in `expand`.
+pub fn repeat<'a>(body: &[Stmt<'a>], param: Option<(&'a str, &Arg<'a>)>, id: usize) -> Result<Vec<Stmt<'a>>> {
+    substitute(body, param.into_iter().collect(), id, true)
+}
+
+fn substitute<'a>(body: &[Stmt<'a>], args: HashMap<&'a str, &Arg<'a>>, id: usize, at_use: bool)
+    -> Result<Vec<Stmt<'a>>>
+{
+    let mut locals = HashSet::new();
+    for stmt in body {
+        let args = match stmt {
+            Stmt::Label(lbl) if lbl.0.starts_with('.') => {
+                locals.insert(lbl.0);
+                continue;
+            },
+            Stmt::Inst(inst) => &inst.args,
+            Stmt::Call(call) => &call.args,
+            _                => continue,
+        };
+
+        for arg in args {
+            if let Arg::Lbl(lbl) = arg {
+                locals.insert(lbl.0);
+            }
+        }
+    }
+
+    let expansion = Expansion { args, locals, id, at_use };
+    body.iter().map(|stmt| expansion.stmt(stmt)).collect()
+}
+
+impl<'m, 'a> Expansion<'m, 'a> {
+    fn stmt(&self, stmt: &Stmt<'a>) -> Result<Stmt<'a>> {
+        let expanded = match stmt {
+            Stmt::Label(lbl) => Stmt::Label(self.label(lbl)),
+            Stmt::Inst(inst) => Stmt::Inst(Inst::new(inst.op, self.args(&inst.args)?, inst.span.clone())),
+            Stmt::Call(call) => Stmt::Call(Call { name: call.name, args: self.args(&call.args)?, span: call.span.clone() }),
+            Stmt::Lit(lit)   => Stmt::Lit(self.lit(lit)?),
+            Stmt::Org(lit)   => Stmt::Org(self.lit(lit)?),
+            Stmt::Equ(equ)   => Stmt::Equ(Equ { name: equ.name.clone(), value: self.lit(&equ.value)? }),
+            Stmt::Include(i) => Stmt::Include(i.clone()),
+            Stmt::Incbin(b)  => Stmt::Incbin(b.clone()),
+            Stmt::Cond(Cond::If(lit), span)   => Stmt::Cond(Cond::If(self.lit(lit)?), span.clone()),
+            Stmt::Cond(Cond::Elif(lit), span) => Stmt::Cond(Cond::Elif(self.lit(lit)?), span.clone()),
+            Stmt::Cond(cond, span)            => Stmt::Cond(cond.clone(), span.clone()),
+            Stmt::Namespace(..) | Stmt::Global(_) => stmt.clone(),
+            Stmt::Fill(fill) => Stmt::Fill(Fill {
+                count: self.lit(&fill.count)?,
+                value: fill.value.as_ref().map(|value| self.lit(value)).transpose()?,
+                span: fill.span.clone(),
+            }),
+            Stmt::Word(lits) => Stmt::Word(lits.iter().map(|lit| self.lit(lit)).collect::<Result<_>>()?),
+            Stmt::Rept(rept) => Stmt::Rept(Rept {
+                kind: match &rept.kind {
+                    ReptKind::Count(count)       => ReptKind::Count(self.lit(count)?),
+                    ReptKind::Irp(param, values) => ReptKind::Irp(param.clone(),
+                                                                  values.iter().map(|v| self.lit(v)).collect::<Result<_>>()?),
+                    ReptKind::Irpc(..)           => rept.kind.clone(),
+                },
+                body: rept.body.iter().map(|stmt| self.stmt(stmt)).collect::<Result<_>>()?,
+                span: rept.span.clone(),
+            }),
+            Stmt::Macro(_)   => unreachable!("nested macros are rejected when parsing"),
+        };
+        Ok(expanded)
+    }
+
+    fn args(&self, args: &[Arg<'a>]) -> Result<Vec<Arg<'a>>> {
+        args.iter().map(|arg| self.arg(arg)).collect()
+    }
+
+    fn arg(&self, arg: &Arg<'a>) -> Result<Arg<'a>> {
+        match arg {
+            Arg::Lbl(lbl)           => Ok(Arg::Lbl(self.label(lbl))),
+            // An argument may take the place of a whole argument, including argument labels.
+            Arg::Lit(Lit::Name(n))  => Ok(match self.param(n) {
+                Some(Arg::Lit(lit)) => Arg::Lit(self.value(lit, n)),
+                Some(param)         => param.clone(),
+                None                => arg.clone(),
+            }),
+            Arg::Lit(lit)           => Ok(Arg::Lit(self.lit(lit)?)),
+        }
+    }
+
+    fn lit(&self, lit: &Lit<'a>) -> Result<Lit<'a>> {
+        match lit {
+            Lit::Lbl(lbl)     => Ok(Lit::Lbl(self.label(lbl))),
+            Lit::Ref(box r)   => Ok(Lit::Ref(Box::new(self.lit(r)?))),
+            Lit::Deref(box d) => Ok(Lit::Deref(Box::new(self.lit(d)?))),
+            Lit::Expr(box e)  => Ok(Lit::Expr(Box::new(Expr {
+                op: e.op,
+                lhs: self.lit(&e.lhs)?,
+                rhs: self.lit(&e.rhs)?,
+                span: e.span.clone(),
+            }))),
+            Lit::SizeOf(s, span) => Ok(Lit::SizeOf(Box::new(self.lit(s)?), span.clone())),
+            Lit::Name(n)      => match self.param(n) {
+                Some(Arg::Lit(lit)) => Ok(self.value(lit, n)),
+                Some(Arg::Lbl(_))   => error!(format!("`{}` is an argument label, which is not allowed here", n.inner), n.span()),
+                // Not a parameter, so it must be a constant.
+                None                => Ok(lit.clone()),
+            },
+            other             => Ok(other.clone()),
+        }
+    }
+
+    fn value(&self, lit: &Lit<'a>, param: &Str<'a>) -> Lit<'a> {
+        if self.at_use { respan(lit, &param.span) } else { lit.clone() }
+    }
+
+    fn param(&self, name: &Str<'a>) -> Option<&'m Arg<'a>> {
+        self.args.get(name.inner).copied()
+    }
+
+    fn label(&self, lbl: &Label<'a>) -> Label<'a> {
+        if lbl.1 == 0 && self.locals.contains(lbl.0) {
+            Spanned::new((lbl.0, self.id), lbl.span())
+        } else {
+            lbl.clone()
+        }
+    }
+}
+
+/// `lit` with every part of it spanning `span`.
+fn respan<'a>(lit: &Lit<'a>, span: &Span<'a>) -> Lit<'a> {
+    match lit {
+        Lit::Lbl(lbl)     => Lit::Lbl(Spanned::new(lbl.inner, span.clone())),
+        Lit::Num(num)     => Lit::Num(Spanned::new(num.inner, span.clone())),
+        Lit::Str(s)       => Lit::Str(Spanned::new(s.inner, span.clone())),
+        Lit::Chr(c)       => Lit::Chr(Spanned::new(c.inner, span.clone())),
+        Lit::Name(n)      => Lit::Name(Spanned::new(n.inner, span.clone())),
+        Lit::Ref(box r)   => Lit::Ref(Box::new(respan(r, span))),
+        Lit::Deref(box d) => Lit::Deref(Box::new(respan(d, span))),
+        Lit::Expr(box e)  => Lit::Expr(Box::new(Expr {
+            op: e.op,
+            lhs: respan(&e.lhs, span),
+            rhs: respan(&e.rhs, span),
+            span: span.clone(),
+        })),
+        Lit::SizeOf(box s, _) => Lit::SizeOf(Box::new(respan(s, span)), span.clone()),
+        Lit::Here(_)      => Lit::Here(span.clone()),
+    }
+}
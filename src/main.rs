@@ -1,66 +1,322 @@
 #![allow(dead_code)]
 #![feature(box_patterns)]
-#![feature(btree_drain_filter)]
 
 use clap::clap_app;
 
 use std::io::Write;
 use std::fs;
+use std::path::PathBuf;
 
 mod ast;
 mod parser;
 mod codegen;
+mod machine;
+mod debugger;
+mod lint;
+mod macros;
+mod source;
+mod encoding;
 
-use crate::parser::parse_asm;
-use crate::codegen::Assembler;
+use crate::source::{ Sources, Texts };
+use crate::codegen::{ Assembler, DebugInfo };
+use crate::machine::Machine;
+use crate::debugger::Debugger;
+use crate::lint::{ Lint, Lints, Level };
+use crate::encoding::Encoding;
+
+enum TapeSize {
+    Fixed(usize),
+    // Fits the program plus this many free cells.
+    Auto(usize),
+}
+
+fn parse_size(matches: &clap::ArgMatches) -> TapeSize {
+    let parse = |arg, default| {
+        match matches.value_of(arg).map(str::parse).unwrap_or(Ok(default)) {
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("{}", matches.usage());
+                std::process::exit(1)
+            },
+            Ok(n) => n,
+        }
+    };
+
+    if matches.value_of("size") == Some("auto") {
+        TapeSize::Auto(parse("stack", 0))
+    } else {
+        TapeSize::Fixed(parse("size", 256))
+    }
+}
+
+fn parse_encoding(matches: &clap::ArgMatches) -> Encoding {
+    // Clap already made sure that the name is valid.
+    matches.value_of("encoding").and_then(Encoding::from_name).unwrap_or_default()
+}
+
+/// The constants defined with `-D NAME=VALUE`, or `-D NAME` for 1.
+fn parse_defines<'m>(matches: &'m clap::ArgMatches) -> Vec<(&'m str, i32)> {
+    let mut defines = Vec::new();
+    for def in matches.values_of("define").into_iter().flatten() {
+        let (name, value) = def.split_once('=').unwrap_or((def, "1"));
+        if !parser::is_ident(name) {
+            eprintln!("invalid define `{}`: `{}` is not a valid name", def, name);
+            eprintln!("{}", matches.usage());
+            std::process::exit(1)
+        }
+        match parser::parse_num(value) {
+            Ok(value) => defines.push((name, value)),
+            Err(e)    => {
+                eprintln!("invalid define `{}`: `{}` is not a number, {}", def, value, e);
+                eprintln!("{}", matches.usage());
+                std::process::exit(1)
+            },
+        }
+    }
+    defines
+}
+
+fn parse_lints(matches: &clap::ArgMatches) -> Lints {
+    // Later flags take precedence, so they are applied in the order they were given.
+    let mut flags = Vec::new();
+    for &(arg, level) in &[("allow", Level::Allow), ("warn", Level::Warn), ("deny", Level::Deny)] {
+        if let (Some(indices), Some(names)) = (matches.indices_of(arg), matches.values_of(arg)) {
+            flags.extend(indices.zip(names).map(|(i, name)| (i, name, level)));
+        }
+    }
+    flags.sort_by_key(|&(i, _, _)| i);
+
+    let mut lints = Lints::default();
+    for (_, name, level) in flags {
+        if let Err(e) = lints.set(name, level) {
+            eprintln!("{}", e);
+            eprint!("available lints:");
+            for lint in &Lint::ALL {
+                eprint!(" {} ({})", lint.name(), lint.code());
+            }
+            eprintln!();
+            std::process::exit(1)
+        }
+    }
+    lints
+}
+
+fn print_errors(sources: &Sources, errors: &[parser::Error]) {
+    for e in errors {
+        eprintln!("{}\n", sources.format_error(e));
+    }
+
+    if errors.len() == 1 {
+        eprintln!("aborting due to previous error");
+    } else {
+        eprintln!("aborting due to {} previous errors", errors.len());
+    }
+}
+
+/// Reads `file` and every file it includes into `texts`, exiting if any of them could not be
+/// parsed.
+fn load<'a>(texts: &'a Texts, file: &str, matches: &clap::ArgMatches) -> std::io::Result<Sources<'a>> {
+    let text = fs::read_to_string(file)?;
+    let include_dirs: Vec<PathBuf> = matches.values_of("include").into_iter().flatten().map(PathBuf::from).collect();
+    let (sources, errors) = Sources::load(texts, file, text, &include_dirs);
+    if !errors.is_empty() {
+        print_errors(&sources, &errors);
+        std::process::exit(1)
+    }
+    Ok(sources)
+}
+
+fn compile<'a>(sources: &Sources<'a>, defines: &[(&'a str, i32)], tape_size: TapeSize, expand: bool, mut lints: Lints,
+               encoding: Encoding)
+    -> Option<(Vec<i32>, DebugInfo<'a>)>
+{
+    for file in sources.libraries() {
+        lints.ignore_file(file);
+    }
+
+    let stmts = sources.stmts();
+    let tape_size = match tape_size {
+        TapeSize::Fixed(size) => Ok(size),
+        TapeSize::Auto(stack) => Assembler::fit(stmts, defines, encoding).map(|size| size + stack),
+    };
+    let res = tape_size.and_then(|tape_size| {
+        let mut asm = Assembler::new(tape_size, expand, lints, encoding);
+        for &(name, value) in defines {
+            asm.define(name, value);
+        }
+        let (res, warnings) = asm.assemble_with_info(stmts);
+        for warning in &warnings {
+            eprintln!("{}\n", sources.format_error(warning));
+        }
+        res
+    });
+    match res {
+        Ok(res) => Some(res),
+        Err(errors) => {
+            print_errors(sources, &errors);
+            None
+        },
+    }
+}
+
+fn run(matches: &clap::ArgMatches) -> std::io::Result<()> {
+    // Ok, FILE is required.
+    let file = matches.value_of("FILE").unwrap();
+    let max_steps = match matches.value_of("max_steps").map(str::parse).transpose() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", matches.usage());
+            std::process::exit(1)
+        },
+    };
+
+    let tape = if matches.is_present("tape") || !file.ends_with(".asm") {
+        match machine::parse_tape(&fs::read_to_string(file)?) {
+            Ok(tape) => tape,
+            Err(e) => {
+                eprintln!("invalid tape file: {}", e);
+                std::process::exit(1)
+            },
+        }
+    } else {
+        let texts = Texts::default();
+        let sources = load(&texts, file, matches)?;
+        match compile(&sources, &parse_defines(matches), parse_size(matches), false, Lints::default(),
+                      parse_encoding(matches)) {
+            Some((tape, _)) => tape,
+            None => std::process::exit(1),
+        }
+    };
+
+    let mut machine = Machine::new(tape, parse_encoding(matches));
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    match machine.run(&mut out, max_steps) {
+        Ok(machine::State::Halted) => Ok(()),
+        Ok(machine::State::Running) => {
+            eprintln!("stopped after {} steps without halting", machine.steps());
+            std::process::exit(1)
+        },
+        Err(e) => {
+            out.flush()?;
+            eprintln!("runtime error: {}", e);
+            std::process::exit(1)
+        },
+    }
+}
+
+fn debug(matches: &clap::ArgMatches) -> std::io::Result<()> {
+    // Ok, SOURCE is required.
+    let src_file = matches.value_of("SOURCE").unwrap();
+    let texts = Texts::default();
+    let sources = load(&texts, src_file, matches)?;
+
+    let encoding = parse_encoding(matches);
+    match compile(&sources, &parse_defines(matches), parse_size(matches), false, Lints::default(), encoding) {
+        Some((tape, info)) => Debugger::new(tape, info, encoding).repl(),
+        None => std::process::exit(1),
+    }
+}
 
 fn main() -> std::io::Result<()> {
     let matches = clap_app!(tapec =>
         (version: "0.1.0")
         (author: "Gabriel Dertoni <gab.dertoni@gmail.com>")
         (about: "A compiler for the Tape programming language")
+        (@setting SubcommandsNegateReqs)
         (@arg SOURCE: +required "The TapeLang source file to compile")
         (@arg output: -o --output +takes_value "Output compiled tape")
-        (@arg size: -s --size +takes_value "Size of the tape")
+        (@arg size: -s --size +takes_value "Size of the tape, or `auto` to fit the program")
+        (@arg stack: --stack +takes_value "Free cells left at the end of the tape with `--size auto`")
         (@arg expand: -E --expand "Compile and also print the desugared code")
+        (@arg debug_info: --("debug-info") +takes_value "Output a map from tape addresses to the source")
+        (@arg listing: -l --listing +takes_value "Output a listing of the source with the generated cells")
+        (@arg symbols: --symbols +takes_value "Output the address of every label")
+        (@arg symbols_format: --("symbols-format") +takes_value possible_values(&["text", "json"])
+            "Format of the symbol table (default: text)")
+        (@arg warn: -W +takes_value +multiple number_of_values(1) "Report a lint (or `all`) as a warning")
+        (@arg allow: -A +takes_value +multiple number_of_values(1) "Don't report a lint (or `all`)")
+        (@arg deny: --deny +takes_value +multiple number_of_values(1) "Report a lint (or `all`) as an error")
+        (@arg include: -I +takes_value +multiple number_of_values(1) "Add a directory to search for included files")
+        (@arg define: -D --define +takes_value +multiple number_of_values(1) "Define a constant, as NAME=VALUE or NAME (which is 1)")
+        (@arg encoding: --encoding +takes_value possible_values(&Encoding::NAMES)
+            "How strings and characters are stored in cells (default: utf8)")
+        (@subcommand run =>
+            (about: "Runs a TapeLang source file or a compiled tape")
+            (@arg FILE: +required "The source file (.asm) or tape to run")
+            (@arg tape: -t --tape "Treat FILE as a compiled tape regardless of its extension")
+            (@arg size: -s --size +takes_value "Size of the tape when compiling FILE, or `auto`")
+            (@arg stack: --stack +takes_value "Free cells left at the end of the tape with `--size auto`")
+            (@arg max_steps: --("max-steps") +takes_value "Stop after executing this many instructions")
+            (@arg include: -I +takes_value +multiple number_of_values(1) "Add a directory to search for included files")
+            (@arg define: -D --define +takes_value +multiple number_of_values(1) "Define a constant, as NAME=VALUE or NAME (which is 1)")
+            (@arg encoding: --encoding +takes_value possible_values(&Encoding::NAMES)
+                "How strings and characters are stored in cells and printed by `put` (default: utf8)")
+        )
+        (@subcommand debug =>
+            (about: "Runs a TapeLang source file step by step")
+            (@arg SOURCE: +required "The TapeLang source file to debug")
+            (@arg size: -s --size +takes_value "Size of the tape, or `auto` to fit the program")
+            (@arg stack: --stack +takes_value "Free cells left at the end of the tape with `--size auto`")
+            (@arg include: -I +takes_value +multiple number_of_values(1) "Add a directory to search for included files")
+            (@arg define: -D --define +takes_value +multiple number_of_values(1) "Define a constant, as NAME=VALUE or NAME (which is 1)")
+            (@arg encoding: --encoding +takes_value possible_values(&Encoding::NAMES)
+                "How strings and characters are stored in cells and printed by `put` (default: utf8)")
+        )
     ).get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("run") {
+        return run(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("debug") {
+        return debug(matches);
+    }
+
     // Ok, SOURCE is required.
     let src_file = matches.value_of("SOURCE").unwrap();
     let out = matches.value_of("output").unwrap_or("a.out");
     let expand = matches.is_present("expand");
-    let tape_size = matches.value_of("size")
-        .map(str::parse)
-        .unwrap_or(Ok(256));
+    let tape_size = parse_size(&matches);
 
-    let tape_size = match tape_size {
-        Err(e) => {
-            eprintln!("{}", e);
-            eprintln!("{}", matches.usage());
-            std::process::exit(1)
-        },
-        Ok(n) => n,
+    let texts = Texts::default();
+    let sources = load(&texts, src_file, &matches)?;
+
+    let defines = parse_defines(&matches);
+    let (tape, info) = match compile(&sources, &defines, tape_size, expand, parse_lints(&matches),
+                                     parse_encoding(&matches)) {
+        Some(res) => res,
+        None      => std::process::exit(1),
     };
 
-    let source = fs::read_to_string(src_file)?;
-
-    match parse_asm(&source).and_then(|p| {
-        let asm = Assembler::new(tape_size, expand);
-        asm.assemble(&p.stmts)
-    }) {
-        Ok(tape) => {
-            if out == "-" {
-                for n in tape {
-                    println!("{}", n);
-                }
-            } else {
-                let mut file = fs::File::create(out)?;
-                for n in tape {
-                    writeln!(file, "{}", n)?;
-                }
-            }
-        },
-        Err(e) => eprintln!("{}", e),
+    if let Some(map) = matches.value_of("debug_info") {
+        let mut file = fs::File::create(map)?;
+        info.write_map(&mut file, &sources)?;
+    }
+
+    if let Some(listing) = matches.value_of("listing") {
+        let mut file = fs::File::create(listing)?;
+        info.write_listing(&mut file, &sources, &tape)?;
+    }
+
+    if let Some(symbols) = matches.value_of("symbols") {
+        let mut file = fs::File::create(symbols)?;
+        match matches.value_of("symbols_format") {
+            Some("json") => info.write_symbols_json(&mut file)?,
+            _            => info.write_symbols(&mut file)?,
+        }
+    }
+
+    if out == "-" {
+        for n in tape {
+            println!("{}", n);
+        }
+    } else {
+        let mut file = fs::File::create(out)?;
+        for n in tape {
+            writeln!(file, "{}", n)?;
+        }
     }
 
     Ok(())
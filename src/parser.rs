@@ -1,7 +1,7 @@
 use pest_derive::Parser;
 use pest::Parser;
-use pest::Span;
-use pest::iterators::Pair;
+use pest::error::ErrorVariant;
+use pest::iterators::{ Pair, Pairs };
 
 use crate::ast::*;
 
@@ -9,80 +9,242 @@ use crate::ast::*;
 #[grammar = "asm.pest"]
 pub struct ASMParser;
 
-pub type Error = pest::error::Error<Rule>;
+/// An error in one of the files of the program, which is named when the error is printed.
+#[derive(Debug, Clone)]
+pub struct Error {
+    inner: pest::error::Error<Rule>,
+    file: usize,
+    // A second, related location.
+    note: Option<Box<Error>>,
+}
+
+pub type Errors = Vec<Error>;
+
+impl Error {
+    pub fn new(message: String, span: Span<'_>) -> Error {
+        let inner = pest::error::Error::new_from_span(ErrorVariant::CustomError { message }, span.inner());
+        Error { inner, file: span.file, note: None }
+    }
+
+    /// Also points at `note`, which is printed as part of the message.
+    pub fn with_note(mut self, note: Error) -> Error {
+        self.note = Some(Box::new(note));
+        self
+    }
+
+    /// The file of the error and where it starts in it.
+    pub fn location(&self) -> (usize, usize) {
+        match self.inner.location {
+            pest::error::InputLocation::Pos(pos)       => (self.file, pos),
+            pest::error::InputLocation::Span((pos, _)) => (self.file, pos),
+        }
+    }
+
+    /// Formats the error, naming its file with `path`.
+    pub fn format(&self, path: &impl Fn(usize) -> String) -> String {
+        let mut inner = self.inner.clone();
+        if let (Some(note), ErrorVariant::CustomError { message }) = (&self.note, &mut inner.variant) {
+            // Errors can only have a single span, so the note is rendered as part of the message.
+            message.push_str(&format!("\n\n{}", note.format(path)));
+        }
+        inner.with_path(&path(self.file)).to_string()
+    }
+}
 
 macro_rules! error {
     ($msg:expr, $span:expr) => {
-        Err(pest::error::Error::new_from_span(pest::error::ErrorVariant::CustomError { message: $msg.into() }, $span))
+        Err(Error::new($msg.into(), $span))
     };
 }
 
-fn parse_label(pair: Pair<Rule>) -> Result<Label, Error> {
+fn parse_label(pair: Pair<Rule>, file: usize) -> Result<Label, Error> {
     let ident = pair
         .into_inner()
         .next()
         .unwrap();
 
-    Ok(mk_lbl(ident.as_str(), ident.as_span()))
+    Ok(mk_lbl(ident.as_str(), Span::new(ident.as_span(), file)))
+}
+
+/// Decodes an escape sequence, like `\n`, `\x1b` or `\u{1F600}`.
+pub fn parse_escape(esc: &str) -> Result<char, String> {
+    let c = match &esc[1..] {
+        "n"  => '\n',
+        "r"  => '\r',
+        "t"  => '\t',
+        "e"  => '\x1b',
+        "0"  => '\0',
+        "\\" => '\\',
+        "'"  => '\'',
+        "\"" => '"',
+        code if code.starts_with('x') => match u8::from_str_radix(&code[1..], 16) {
+            Ok(byte) if code.len() == 3 && byte <= 0x7f => byte as char,
+            Ok(_) if code.len() == 3 => return Err(format!("`{}` is not ASCII, use `\\u{{...}}` instead", esc)),
+            _                        => return Err(format!("`{}` must be followed by two hex digits", &esc[..2])),
+        },
+        code if code.starts_with("u{") => {
+            let hex = &code[2..code.len() - 1];
+            match u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                Some(c) => c,
+                None    => return Err(format!("`{}` is not a valid unicode character", esc)),
+            }
+        },
+        "u"  => return Err("`\\u` must be followed by a character code in braces, like `\\u{1F600}`".to_string()),
+        _    => return Err(format!("invalid escape `{}`", esc)),
+    };
+    Ok(c)
+}
+
+/// The length of the escape sequence at the start of `s`, just like the `escape` rule.
+fn escape_len(s: &str) -> usize {
+    let code = &s[1..];
+    if let Some(hex) = code.strip_prefix('x') {
+        2 + hex.chars().take(2).take_while(char::is_ascii_hexdigit).count()
+    } else if let Some(digits) = code.strip_prefix("u{") {
+        let len = digits.chars().take_while(char::is_ascii_alphanumeric).count();
+        if digits[len..].starts_with('}') { 4 + len } else { 2 }
+    } else {
+        1 + code.chars().next().map_or(0, char::len_utf8)
+    }
 }
 
-fn extract_str<'a>(s: &'a str) -> Result<&'a str, Error> {
-    let inner = &s[1..s.len()-1];
-    Ok(inner)
+/// The characters of the text of a string literal, with its escapes replaced.
+pub fn unescape(s: &str) -> Result<Vec<char>, String> {
+    let mut chars = Vec::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        chars.extend(rest[..i].chars());
+        let len = escape_len(&rest[i..]);
+        chars.push(parse_escape(&rest[i..i + len])?);
+        rest = &rest[i + len..];
+    }
+    chars.extend(rest.chars());
+    Ok(chars)
 }
 
-fn extract_chr(s: &str) -> char {
-    match &s[1..s.len()-1] {
-        "\\n"  => '\n',
-        "\\r"  => '\r',
-        "\\t"  => '\t',
-        "\\\\" => '\\',
-        "\\'"  => '\'',
-        "\\0"  => '\0',
-        s      => s.chars().nth(0).unwrap(),
+fn extract_str<'a>(pair: Pair<'a, Rule>, file: usize) -> Result<&'a str, Error> {
+    // Escapes are replaced when assembling, but bad ones are reported here where their span is known.
+    for esc in pair.clone().into_inner() {
+        parse_escape(esc.as_str()).map_err(|msg| Error::new(msg, Span::new(esc.as_span(), file)))?;
     }
+
+    let s = pair.as_str();
+    Ok(&s[1..s.len()-1])
 }
 
-fn parse_lbl(pair: Pair<Rule>) -> Result<Label, Error> {
+fn extract_chr(pair: Pair<Rule>, file: usize) -> Result<char, Error> {
+    match pair.clone().into_inner().next() {
+        Some(esc) => parse_escape(esc.as_str()).map_err(|msg| Error::new(msg, Span::new(esc.as_span(), file))),
+        None      => Ok(pair.as_str()[1..].chars().next().unwrap()),
+    }
+}
+
+/// Parses a number in any base. Numbers written in hex, binary or octal may use all of the 32 bits,
+/// so `0xFFFFFFFF` is -1, but with a `-` they can go no further than `-0x80000000`.
+pub fn parse_num(s: &str) -> Result<i32, String> {
+    let s = s.replace('_', "");
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None         => (false, s.as_str()),
+    };
+
+    let radix = match digits.get(..2) {
+        Some("0x") => 16,
+        Some("0b") => 2,
+        Some("0o") => 8,
+        _          => return s.parse().map_err(|e: std::num::ParseIntError| e.to_string()),
+    };
+
+    let val = u32::from_str_radix(&digits[2..], radix).map_err(|e| e.to_string())?;
+    match (neg, val) {
+        (false, _)              => Ok(val as i32),
+        // `-0x80000000` is the smallest number, whose negation is itself.
+        (true, 0..=0x8000_0000) => Ok((val as i32).wrapping_neg()),
+        (true, _)               => Err("number out of range, the smallest one is -0x80000000".to_string()),
+    }
+}
+
+/// Whether `s` is a valid name for a label or a constant, just like the `ident` rule.
+pub fn is_ident(s: &str) -> bool {
+    ASMParser::parse(Rule::ident, s).is_ok_and(|pairs| pairs.as_str() == s)
+}
+
+fn parse_lbl(pair: Pair<Rule>, file: usize) -> Result<Label, Error> {
     let ident = pair
         .into_inner()
         .next()
         .unwrap();
 
-    Ok(mk_lbl(ident.as_str(), ident.as_span()))
+    Ok(mk_lbl(ident.as_str(), Span::new(ident.as_span(), file)))
 }
 
-fn parse_deref(pair: Pair<Rule>) -> Result<Lit, Error> {
+fn parse_deref(pair: Pair<Rule>, file: usize) -> Result<Lit, Error> {
     let deref = pair
         .into_inner()
         .next()
         .unwrap();
 
     let parsed = match deref.as_rule() {
-        Rule::lit_deref => parse_deref(deref)?,
-        _               => parse_lit(deref)?,
+        Rule::lit_deref => parse_deref(deref, file)?,
+        _               => parse_lit(deref, file)?,
     };
 
     Ok(Lit::Deref(Box::new(parsed)))
 }
 
-fn parse_lit(pair: Pair<Rule>) -> Result<Lit, Error> {
-    let lit = pair
-        .into_inner()
-        .next()
-        .unwrap();
+/// Parses a sequence of operands joined by operators, which are all of the same precedence.
+fn parse_binary(pair: Pair<Rule>, file: usize) -> Result<Lit, Error> {
+    let start = pair.as_span().start_pos();
+    let mut pairs = pair.into_inner();
+    let mut lhs = parse_value(pairs.next().unwrap(), file)?;
 
-    let span = lit.as_span();
+    while let Some(op) = pairs.next() {
+        let op = match op.as_str() {
+            "+"  => BinOp::Add,
+            "-"  => BinOp::Sub,
+            "*"  => BinOp::Mul,
+            "/"  => BinOp::Div,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "<"  => BinOp::Lt,
+            "<=" => BinOp::Le,
+            ">"  => BinOp::Gt,
+            ">=" => BinOp::Ge,
+            _    => unreachable!(),
+        };
+        let rhs = pairs.next().unwrap();
+        let span = Span::new(start.span(&rhs.as_span().end_pos()), file);
+        let rhs = parse_value(rhs, file)?;
+        lhs = Lit::Expr(Box::new(Expr { op, lhs, rhs, span }));
+    }
+
+    Ok(lhs)
+}
+
+fn parse_lit(pair: Pair<Rule>, file: usize) -> Result<Lit, Error> {
+    parse_value(pair.into_inner().next().unwrap(), file)
+}
+
+fn parse_value(lit: Pair<Rule>, file: usize) -> Result<Lit, Error> {
+    let span = Span::new(lit.as_span(), file);
     let parsed = match lit.as_rule() {
-        Rule::lbl       => Lit::Lbl(parse_lbl(lit)?),
-        Rule::num       => match lit.as_str().parse() {
+        Rule::lbl       => Lit::Lbl(parse_lbl(lit, file)?),
+        Rule::num       => match parse_num(lit.as_str()) {
                             Ok(n)  => Lit::Num(Spanned::new(n, span)),
                             Err(e) => return error!(e.to_string(), span),
                           },
-        Rule::str       => Lit::Str(Spanned::new(extract_str(lit.as_str())?, span)),
-        Rule::chr       => Lit::Chr(Spanned::new(extract_chr(lit.as_str()), span)),
-        Rule::lit_ref   => Lit::Ref(Box::new(parse_lit(lit.into_inner().next().unwrap())?)),
-        Rule::lit_deref => parse_deref(lit)?,
+        Rule::str       => Lit::Str(Spanned::new(extract_str(lit, file)?, span)),
+        Rule::chr       => Lit::Chr(Spanned::new(extract_chr(lit, file)?, span)),
+        Rule::lit_ref   => Lit::Ref(Box::new(parse_lit(lit.into_inner().next().unwrap(), file)?)),
+        Rule::lit_deref => parse_deref(lit, file)?,
+        Rule::name      => Lit::Name(Spanned::new(lit.as_str(), span)),
+        Rule::comparison |
+        Rule::expr |
+        Rule::product   => parse_binary(lit, file)?,
+        Rule::lit       => parse_lit(lit, file)?,
+        Rule::paren     => parse_lit(lit, file)?,
+        Rule::here      => Lit::Here(span),
+        Rule::size_of   => Lit::SizeOf(Box::new(parse_value(lit.into_inner().next().unwrap(), file)?), span),
         _               => unreachable!(),
     };
 
@@ -99,15 +261,15 @@ fn extract_arg_lbl<'a>(s: &'a str, span: Span<'a>) -> Result<Label<'a>, Error> {
     }
 }
 
-fn parse_arg(pair: Pair<Rule>) -> Result<Arg, Error> {
+fn parse_arg(pair: Pair<Rule>, file: usize) -> Result<Arg, Error> {
     let arg = pair
         .into_inner()
         .next()
         .unwrap();
 
-    let span = arg.as_span();
+    let span = Span::new(arg.as_span(), file);
     let parsed = match arg.as_rule() {
-        Rule::lit     => Arg::Lit(parse_lit(arg)?),
+        Rule::lit     => Arg::Lit(parse_lit(arg, file)?),
         Rule::arg_lbl => {
             let lbl = extract_arg_lbl(arg.as_str(), span.clone())?;
             Arg::Lbl(lbl)
@@ -117,73 +279,361 @@ fn parse_arg(pair: Pair<Rule>) -> Result<Arg, Error> {
     Ok(parsed)
 }
 
-fn parse_inst(pair: Pair<Rule>) -> Result<Inst, Error> {
+fn parse_inst(pair: Pair<Rule>, file: usize) -> Result<Stmt, Error> {
     let mut inst_iter = pair.into_inner();
     let ident = inst_iter.next().unwrap();
 
-    let op = match ident.as_str() {
-        "hlt" => Op::Hlt,
-        "add" => Op::Add,
-        "mul" => Op::Mul,
-        "cle" => Op::Cle,
-        "ceq" => Op::Ceq,
-        "jmp" => Op::Jmp,
-        "beq" => Op::Beq,
-        "cpy" => Op::Cpy,
-        "put" => Op::Put,
-        "ptn" => Op::Ptn,
-
-        "psh" => Op::Psh,
-        "pop" => Op::Pop,
-        "cal" => Op::Cal,
-        "ret" => Op::Ret,
-        _     => return error!("not a valid instruction", ident.as_span()),
-    };
-
-    let span = ident.as_span();
+    let span = Span::new(ident.as_span(), file);
     let arg_lst: Vec<_> = inst_iter.collect();
 
+    let op = match Op::from_name(ident.as_str()) {
+        Some(op) => op,
+        // Anything else may be a macro, which is only known when assembling.
+        None     => {
+            let args: Result<Vec<_>, _> = arg_lst.into_iter().map(|pair| parse_arg(pair, file)).collect();
+            return Ok(Stmt::Call(Call { name: ident.as_str(), args: args?, span }));
+        },
+    };
+
     if arg_lst.len() == op.nargs() {
-        let args: Result<Vec<_>, _> = arg_lst.into_iter().map(parse_arg).collect();
+        let args: Result<Vec<_>, _> = arg_lst.into_iter().map(|pair| parse_arg(pair, file)).collect();
         let args = args?;
-        Ok(Inst { op, args, span })
+        Ok(Stmt::Inst(Inst { op, args, span }))
     } else {
         error!(format!("expected {} argument(s) but got {}", op.nargs(), arg_lst.len()), span)
     }
 }
 
-fn parse_stmt(pair: Pair<Rule>) -> Result<Stmt, Error> {
+fn parse_macro<'a>(pair: Pair<'a, Rule>, errors: &mut Errors, file: usize) -> Result<Macro<'a>, Error> {
+    let span = Span::new(pair.as_span(), file);
+    let mut macro_iter = pair.into_inner();
+    let mut head = macro_iter.next().unwrap().into_inner();
+
+    let name = head.next().unwrap();
+    let name = Spanned::new(name.as_str(), Span::new(name.as_span(), file));
+
+    let mut params: Vec<Str> = Vec::new();
+    for param in head {
+        if params.iter().any(|p| p.inner == param.as_str()) {
+            return error!("parameter defined twice", Span::new(param.as_span(), file));
+        }
+        params.push(Spanned::new(param.as_str(), Span::new(param.as_span(), file)));
+    }
+
+    let body = parse_stmts(macro_iter, errors, file);
+    for stmt in &body {
+        if let Stmt::Macro(inner) = stmt {
+            let res: Result<(), _> = error!("macros cannot be defined inside of other macros", inner.span.clone());
+            errors.extend(res.err());
+        }
+    }
+
+    Ok(Macro { name, params, body, span })
+}
+
+fn parse_rept<'a>(pair: Pair<'a, Rule>, errors: &mut Errors, file: usize) -> Result<Rept<'a>, Error> {
+    let span = Span::new(pair.as_span(), file);
+    let mut rept_iter = pair.into_inner();
+    let head = rept_iter.next().unwrap();
+    let rule = head.as_rule();
+    let mut head = head.into_inner();
+
+    let kind = if rule == Rule::rept_count {
+        ReptKind::Count(parse_lit(head.next().unwrap(), file)?)
+    } else {
+        let param = head.next().unwrap();
+        let param = Spanned::new(param.as_str(), Span::new(param.as_span(), file));
+        if rule == Rule::rept_irp {
+            let values: Result<Vec<_>, _> = head.map(|pair| parse_lit(pair, file)).collect();
+            ReptKind::Irp(param, values?)
+        } else {
+            let chars = head.next().unwrap();
+            ReptKind::Irpc(param, Spanned::new(extract_str(chars.clone(), file)?, Span::new(chars.as_span(), file)))
+        }
+    };
+
+    let body = parse_stmts(rept_iter, errors, file);
+    for stmt in &body {
+        if let Stmt::Macro(inner) = stmt {
+            let res: Result<(), _> = error!("macros cannot be defined inside of repetitions", inner.span.clone());
+            errors.extend(res.err());
+        }
+    }
+
+    Ok(Rept { kind, body, span })
+}
+
+fn parse_stmt<'a>(pair: Pair<'a, Rule>, errors: &mut Errors, file: usize) -> Result<Stmt<'a>, Error> {
     let stmt = pair
         .into_inner()
         .next()
         .unwrap();
 
     match stmt.as_rule() {
-        Rule::label => Ok(Stmt::Label(parse_label(stmt)?)),
-        Rule::inst  => Ok(Stmt::Inst(parse_inst(stmt)?)),
-        Rule::lit   => Ok(Stmt::Lit(parse_lit(stmt)?)),
-        Rule::org   => {
-            let num = stmt.into_inner().next().unwrap();
-            match num.as_str().parse() {
-               Ok(n)  => Ok(Stmt::Org(Spanned::new(n, num.as_span()))),
-               Err(e) => error!(e.to_string(), num.as_span()),
-            }
+        Rule::macro_def => Ok(Stmt::Macro(parse_macro(stmt, errors, file)?)),
+        Rule::rept      => Ok(Stmt::Rept(parse_rept(stmt, errors, file)?)),
+        Rule::label     => Ok(Stmt::Label(parse_label(stmt, file)?)),
+        Rule::inst      => parse_inst(stmt, file),
+        Rule::lit       => Ok(Stmt::Lit(parse_lit(stmt, file)?)),
+        Rule::org       => Ok(Stmt::Org(parse_lit(stmt.into_inner().next().unwrap(), file)?)),
+        Rule::equ       => {
+            let mut equ_iter = stmt.into_inner();
+            let name = equ_iter.next().unwrap();
+            let name = Spanned::new(name.as_str(), Span::new(name.as_span(), file));
+            let value = parse_lit(equ_iter.next().unwrap(), file)?;
+            Ok(Stmt::Equ(Equ { name, value }))
+        },
+        Rule::zero |
+        Rule::fill      => {
+            let span = Span::new(stmt.as_span(), file);
+            let zero = stmt.as_rule() == Rule::zero;
+            let mut fill_iter = stmt.into_inner();
+            let count = parse_lit(fill_iter.next().unwrap(), file)?;
+            let value = if zero { None } else { Some(parse_lit(fill_iter.next().unwrap(), file)?) };
+            Ok(Stmt::Fill(Fill { count, value, span }))
+        },
+        Rule::word      => {
+            let lits: Result<Vec<_>, _> = stmt.into_inner().map(|pair| parse_lit(pair, file)).collect();
+            Ok(Stmt::Word(lits?))
+        },
+        Rule::cond_if     |
+        Rule::cond_elif   => {
+            let span = Span::new(stmt.as_span(), file);
+            let elif = stmt.as_rule() == Rule::cond_elif;
+            let lit = parse_value(stmt.into_inner().next().unwrap(), file)?;
+            Ok(Stmt::Cond(if elif { Cond::Elif(lit) } else { Cond::If(lit) }, span))
+        },
+        Rule::cond_ifdef  |
+        Rule::cond_ifndef => {
+            let span = Span::new(stmt.as_span(), file);
+            let negated = stmt.as_rule() == Rule::cond_ifndef;
+            let name = stmt.into_inner().next().unwrap();
+            let name = Spanned::new(name.as_str(), Span::new(name.as_span(), file));
+            Ok(Stmt::Cond(Cond::IfDef { name, negated }, span))
+        },
+        Rule::namespace     => {
+            let span = Span::new(stmt.as_span(), file);
+            let name = stmt.into_inner().next().unwrap();
+            Ok(Stmt::Namespace(Some(Spanned::new(name.as_str(), Span::new(name.as_span(), file))), span))
+        },
+        Rule::namespace_end => Ok(Stmt::Namespace(None, Span::new(stmt.as_span(), file))),
+        Rule::global        => {
+            let names = stmt.into_inner().map(|name| Spanned::new(name.as_str(), Span::new(name.as_span(), file))).collect();
+            Ok(Stmt::Global(names))
+        },
+        Rule::cond_else   => Ok(Stmt::Cond(Cond::Else, Span::new(stmt.as_span(), file))),
+        Rule::cond_endif  => Ok(Stmt::Cond(Cond::Endif, Span::new(stmt.as_span(), file))),
+        Rule::incbin    => {
+            let span = Span::new(stmt.as_span(), file);
+            let mut bin_iter = stmt.into_inner().peekable();
+            let path = bin_iter.next().unwrap();
+            let path = Spanned::new(extract_str(path.clone(), file)?, Span::new(path.as_span(), file));
+            let format = match bin_iter.peek().map(|p| (p.as_rule(), p.as_str())) {
+                Some((Rule::incbin_format, "tape"))  => IncbinFormat::Tape,
+                Some((Rule::incbin_format, "chars")) => IncbinFormat::Chars,
+                _                                    => IncbinFormat::Bytes,
+            };
+            let lits: Result<Vec<_>, _> = bin_iter.filter(|p| p.as_rule() == Rule::lit).map(|pair| parse_lit(pair, file)).collect();
+            let mut lits = lits?.into_iter();
+            Ok(Stmt::Incbin(Incbin { path, format, offset: lits.next(), length: lits.next(), data: None, span }))
+        },
+        Rule::include   => {
+            let path = stmt.into_inner().next().unwrap();
+            let library = path.as_rule() == Rule::library;
+            let inner = &path.as_str()[1..path.as_str().len() - 1];
+            Ok(Stmt::Include(Include { path: Spanned::new(inner, Span::new(path.as_span(), file)), library, file: None }))
         },
-        _           => unreachable!(),
+        _               => unreachable!(),
     }
 }
 
-pub fn parse_asm(program: &str) -> Result<Prog, Error> {
-    let prog = ASMParser::parse(Rule::asm, program)?.next().unwrap();
-    let span = prog.as_span();
-    let res: Result<Vec<_>, _> = prog
-        .into_inner()
-        .filter(|stmt| stmt.as_rule() == Rule::stmt)
-        .map(parse_stmt)
-        .collect();
-
-    Ok(Prog {
-        stmts: res?,
-        span,
-    })
+fn parse_bad_stmt(pair: Pair<Rule>, file: usize) -> Result<Stmt, Error> {
+    let msg = if pair.as_str().starts_with(".macro") {
+        "`.macro` without a matching `.endm`"
+    } else if pair.as_str().starts_with(".endm") {
+        "`.endm` without a matching `.macro`"
+    } else if [".rept", ".irp"].iter().any(|head| pair.as_str().starts_with(head)) {
+        "repetition without a matching `.endr`"
+    } else if pair.as_str().starts_with(".endr") {
+        "`.endr` without a matching `.rept`, `.irp` or `.irpc`"
+    } else {
+        "invalid statement"
+    };
+    error!(msg, Span::new(pair.as_span(), file))
+}
+
+/// Parses a sequence of statements, keeping the errors in `errors` so that parsing can go on.
+fn parse_stmts<'a>(pairs: Pairs<'a, Rule>, errors: &mut Errors, file: usize) -> Vec<Stmt<'a>> {
+    let mut stmts = Vec::new();
+    for stmt in pairs {
+        let res = match stmt.as_rule() {
+            Rule::stmt           => parse_stmt(stmt, errors, file),
+            Rule::bad_stmt |
+            Rule::macro_bad_stmt |
+            Rule::rept_bad_stmt  => parse_bad_stmt(stmt, file),
+            _                    => continue,
+        };
+
+        match res {
+            Ok(stmt) => stmts.push(stmt),
+            Err(e)   => errors.push(e),
+        }
+    }
+    stmts
+}
+
+/// Parses a whole file, which is `file` in `Sources`. Bad statements are skipped up to the end of
+/// the line, so that every error in the file can be reported at once.
+pub fn parse_asm(program: &str, file: usize) -> Result<Prog<'_>, Errors> {
+    let prog = ASMParser::parse(Rule::asm, program)
+        .map_err(|inner| vec![Error { inner, file, note: None }])?
+        .next()
+        .unwrap();
+    let span = Span::new(prog.as_span(), file);
+
+    let mut errors = Vec::new();
+    let stmts = parse_stmts(prog.into_inner(), &mut errors, file);
+
+    if errors.is_empty() {
+        Ok(Prog { stmts, span })
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_num_decimal() {
+        assert_eq!(parse_num("0"), Ok(0));
+        assert_eq!(parse_num("42"), Ok(42));
+        assert_eq!(parse_num("-42"), Ok(-42));
+        assert_eq!(parse_num("1_000_000"), Ok(1_000_000));
+        assert_eq!(parse_num("-1_000"), Ok(-1_000));
+    }
+
+    #[test]
+    fn parse_num_radix() {
+        assert_eq!(parse_num("0x1F"), Ok(0x1F));
+        assert_eq!(parse_num("0xff"), Ok(0xFF));
+        assert_eq!(parse_num("0b101"), Ok(0b101));
+        assert_eq!(parse_num("0o17"), Ok(0o17));
+        assert_eq!(parse_num("0xFF_FF"), Ok(0xFFFF));
+        assert_eq!(parse_num("0b1111_0000"), Ok(0xF0));
+        assert_eq!(parse_num("-0x10"), Ok(-16));
+        assert_eq!(parse_num("-0b11"), Ok(-3));
+        assert_eq!(parse_num("-0o10"), Ok(-8));
+    }
+
+    #[test]
+    fn parse_num_all_bits() {
+        assert_eq!(parse_num("0xFFFFFFFF"), Ok(-1));
+        assert_eq!(parse_num("0x80000000"), Ok(i32::MIN));
+        assert_eq!(parse_num("0x7FFFFFFF"), Ok(i32::MAX));
+        assert_eq!(parse_num("0b11111111111111111111111111111111"), Ok(-1));
+    }
+
+    #[test]
+    fn parse_num_bounds() {
+        assert_eq!(parse_num("2147483647"), Ok(i32::MAX));
+        assert_eq!(parse_num("-2147483648"), Ok(i32::MIN));
+        assert_eq!(parse_num("-0x80000000"), Ok(i32::MIN));
+        assert_eq!(parse_num("-0x7FFFFFFF"), Ok(-i32::MAX));
+
+        assert!(parse_num("2147483648").is_err());
+        assert!(parse_num("-2147483649").is_err());
+        assert!(parse_num("-0x80000001").is_err());
+        assert!(parse_num("-0xFFFFFFFF").is_err());
+        assert!(parse_num("0x100000000").is_err());
+    }
+
+    #[test]
+    fn parse_num_invalid() {
+        assert!(parse_num("0x").is_err());
+        assert!(parse_num("0b2").is_err());
+        assert!(parse_num("0o8").is_err());
+        assert!(parse_num("12a").is_err());
+        assert!(parse_num("").is_err());
+    }
+
+    #[test]
+    fn parse_escape_all() {
+        let escapes = [
+            ("\\n", '\n'), ("\\r", '\r'), ("\\t", '\t'), ("\\e", '\x1b'), ("\\0", '\0'),
+            ("\\\\", '\\'), ("\\'", '\''), ("\\\"", '"'),
+            ("\\x41", 'A'), ("\\x7f", '\x7f'), ("\\x7F", '\x7f'),
+            ("\\u{41}", 'A'), ("\\u{e7}", 'ç'), ("\\u{1F600}", '😀'), ("\\u{10FFFF}", '\u{10FFFF}'),
+        ];
+        for (esc, c) in escapes {
+            assert_eq!(parse_escape(esc), Ok(c), "{}", esc);
+        }
+    }
+
+    #[test]
+    fn parse_escape_invalid() {
+        assert_eq!(parse_escape("\\q"), Err("invalid escape `\\q`".to_string()));
+        assert_eq!(parse_escape("\\x4"), Err("`\\x` must be followed by two hex digits".to_string()));
+        assert_eq!(parse_escape("\\x"), Err("`\\x` must be followed by two hex digits".to_string()));
+        assert_eq!(parse_escape("\\x80"), Err("`\\x80` is not ASCII, use `\\u{...}` instead".to_string()));
+        assert!(parse_escape("\\u").is_err());
+    }
+
+    #[test]
+    fn parse_escape_unicode_bounds() {
+        assert_eq!(parse_escape("\\u{0}"), Ok('\0'));
+        assert!(parse_escape("\\u{110000}").is_err());
+        // Surrogates are not characters.
+        assert!(parse_escape("\\u{D800}").is_err());
+        assert!(parse_escape("\\u{DFFF}").is_err());
+        assert!(parse_escape("\\u{}").is_err());
+        assert!(parse_escape("\\u{zz}").is_err());
+        assert!(parse_escape("\\u{FFFFFFFFF}").is_err());
+    }
+
+    #[test]
+    fn unescape_str() {
+        assert_eq!(unescape("a\\tb\\x41\\u{e7}\\\\"), Ok(vec!['a', '\t', 'b', 'A', 'ç', '\\']));
+        assert_eq!(unescape("ç😀"), Ok(vec!['ç', '😀']));
+        assert_eq!(unescape(""), Ok(vec![]));
+        assert!(unescape("ok \\q").is_err());
+    }
+
+    #[test]
+    fn escape_len_matches_the_rule() {
+        assert_eq!(escape_len("\\nabc"), 2);
+        assert_eq!(escape_len("\\x41b"), 4);
+        assert_eq!(escape_len("\\x4g"), 3);
+        assert_eq!(escape_len("\\xg"), 2);
+        assert_eq!(escape_len("\\u{1F600}x"), 9);
+        // Without the closing brace only `\\u` is the escape.
+        assert_eq!(escape_len("\\u{41"), 2);
+        assert_eq!(escape_len("\\çx"), 3);
+    }
+
+    // The message and the text of the span of the first error in `program`.
+    fn first_error(program: &str) -> (String, &str) {
+        let err = match parse_asm(program, 0) {
+            Ok(_)       => panic!("`{}` has no errors", program),
+            Err(errors) => errors[0].clone(),
+        };
+        let (start, end) = match err.inner.location {
+            pest::error::InputLocation::Span(span) => span,
+            pest::error::InputLocation::Pos(pos)   => (pos, pos),
+        };
+        let message = match err.inner.variant {
+            ErrorVariant::CustomError { message } => message,
+            variant                               => format!("{:?}", variant),
+        };
+        (message, &program[start..end])
+    }
+
+    #[test]
+    fn invalid_escape_span() {
+        assert_eq!(first_error("msg: \"ab\\qc\"\n"), ("invalid escape `\\q`".to_string(), "\\q"));
+        assert_eq!(first_error("msg: \"\\n\\x80\"\n").1, "\\x80");
+        assert_eq!(first_error("msg: \"\\u{D800}\"\n").1, "\\u{D800}");
+        assert_eq!(first_error("chr: '\\q'\n").1, "\\q");
+    }
 }
@@ -0,0 +1,234 @@
+use std::cell::OnceCell;
+use std::collections::{ BTreeMap, HashMap };
+use std::path::{ Path, PathBuf };
+
+use crate::ast::{ Incbin, Include, Included, Stmt };
+use crate::parser::{ parse_asm, Error, Errors };
+
+// The standard library, which is bundled with the compiler and included with `.include <std/io>`.
+const LIBRARIES: &[(&str, &str)] = &[
+    ("std/io",    include_str!("../std/io.asm")),
+    ("std/mem",   include_str!("../std/mem.asm")),
+    ("std/math",  include_str!("../std/math.asm")),
+    ("std/alloc", include_str!("../std/alloc.asm")),
+];
+
+/// Owns the text of every file of a program. Texts are only ever added, so the statements parsed
+/// from a file can keep borrowing its text while more files are loaded.
+#[derive(Default)]
+pub struct Texts {
+    first: OnceCell<Box<Text>>,
+}
+
+struct Text {
+    text: String,
+    next: OnceCell<Box<Text>>,
+}
+
+impl Texts {
+    fn add(&self, text: String) -> &str {
+        let mut last = &self.first;
+        while let Some(node) = last.get() {
+            last = &node.next;
+        }
+        &last.get_or_init(|| Box::new(Text { text, next: OnceCell::new() })).text
+    }
+}
+
+struct SourceFile<'a> {
+    path: PathBuf,
+    text: &'a str,
+    // Whether it is part of the standard library, which is not linted.
+    library: bool,
+    // The line of every `.include` that loaded a file and the file it loaded, in the order they
+    // appear.
+    includes: Vec<(usize, usize)>,
+}
+
+/// A program made of a main file and every file it includes.
+pub struct Sources<'a> {
+    files: Vec<SourceFile<'a>>,
+    main: usize,
+    // The statements of the program. The statements of every included file are in its `.include`.
+    stmts: Vec<Stmt<'a>>,
+}
+
+impl<'a> Sources<'a> {
+    /// Loads every file included by the main file, which is at `path` and has `text`, parsing each
+    /// of them once. Included files are searched for next to the file that includes them and then
+    /// in `include_dirs`. The errors in the main file are returned along with the files, which are
+    /// needed to print them. The ones in included files are only reported when assembling, if the
+    /// `.include` is not in a branch of an `.if` that is not taken.
+    pub fn load(texts: &'a Texts, path: &str, text: String, include_dirs: &[PathBuf]) -> (Sources<'a>, Errors) {
+        let mut loader = Loader {
+            texts,
+            include_dirs,
+            files: Vec::new(),
+            stmts: Vec::new(),
+            stack: Vec::new(),
+            loaded: HashMap::new(),
+        };
+        let main = loader.load(PathBuf::from(path), text, false);
+        let (stmts, errors) = match loader.stmts.swap_remove(main) {
+            Ok(stmts)   => (stmts, Vec::new()),
+            Err(errors) => (Vec::new(), errors),
+        };
+
+        (Sources { files: loader.files, main, stmts }, errors)
+    }
+
+    pub fn stmts(&self) -> &[Stmt<'a>] {
+        &self.stmts
+    }
+
+    pub fn main(&self) -> usize {
+        self.main
+    }
+
+    pub fn path(&self, file: usize) -> &Path {
+        &self.files[file].path
+    }
+
+    pub fn text(&self, file: usize) -> &'a str {
+        self.files[file].text
+    }
+
+    /// The files included by `file`, by the line of the `.include`.
+    pub fn includes(&self, file: usize) -> BTreeMap<usize, usize> {
+        self.files[file].includes.iter().copied().collect()
+    }
+
+    /// The files of the standard library.
+    pub fn libraries(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.files.len()).filter(move |&file| self.files[file].library)
+    }
+
+    /// Formats an error, naming the file it is in.
+    pub fn format_error(&self, err: &Error) -> String {
+        err.format(&|file| self.path(file).display().to_string())
+    }
+}
+
+struct Loader<'a, 'd> {
+    texts: &'a Texts,
+    include_dirs: &'d [PathBuf],
+    files: Vec<SourceFile<'a>>,
+    // The statements of every file, or the errors found when parsing it, which are copied into every
+    // `.include` of the file.
+    stmts: Vec<Result<Vec<Stmt<'a>>, Errors>>,
+    // The files being loaded, by their canonical path, to find include cycles.
+    stack: Vec<(PathBuf, PathBuf)>,
+    // The files already loaded, by their canonical path.
+    loaded: HashMap<PathBuf, usize>,
+}
+
+impl<'a, 'd> Loader<'a, 'd> {
+    fn load(&mut self, path: PathBuf, text: String, library: bool) -> usize {
+        let file = self.files.len();
+        let text = self.texts.add(text);
+        self.files.push(SourceFile { path: path.clone(), text, library, includes: Vec::new() });
+        self.stmts.push(Ok(Vec::new()));
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        self.stack.push((canonical.clone(), path.clone()));
+
+        let stmts = parse_asm(text, file).map(|prog| {
+            let mut stmts = Vec::new();
+            for mut stmt in prog.stmts {
+                match &mut stmt {
+                    Stmt::Include(inc) => {
+                        let first_new = self.files.len();
+                        inc.file = Some(match self.include(&path, inc) {
+                            Ok(included) => {
+                                // Only the first `.include` of a file is listed along with it.
+                                if included >= first_new {
+                                    let line = inc.path.span.start_pos().line_col().0;
+                                    self.files[file].includes.push((line, included));
+                                }
+                                Included::File(included, self.stmts[included].clone())
+                            },
+                            Err(e)       => Included::Failed(e),
+                        });
+                    },
+                    Stmt::Incbin(bin)  => bin.data = Some(self.read_binary(&path, bin)),
+                    _                  => (),
+                }
+                stmts.push(stmt);
+            }
+            stmts
+        });
+
+        self.stack.pop();
+        self.stmts[file] = stmts;
+        self.loaded.insert(canonical, file);
+        file
+    }
+
+    fn include(&mut self, from: &Path, inc: &Include<'a>) -> Result<usize, Error> {
+        let name = &inc.path;
+        if inc.library {
+            return self.include_library(name.inner)
+                .ok_or_else(|| {
+                    let names: Vec<_> = LIBRARIES.iter().map(|(name, _)| *name).collect();
+                    Error::new(format!("there is no library named `{}`, the available ones are: {}",
+                                       name.inner, names.join(", ")), name.span())
+                });
+        }
+
+        let path = match self.resolve(from, name.inner) {
+            Some(path) => path,
+            None       => return Err(Error::new(format!("cannot find `{}` to include", name.inner), name.span())),
+        };
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if let Some(start) = self.stack.iter().position(|(file, _)| *file == canonical) {
+            let cycle: Vec<_> = self.stack[start..]
+                .iter()
+                .map(|(_, path)| path)
+                .chain(std::iter::once(&path))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(Error::new(format!("`{}` is included recursively: {}", name.inner, cycle.join(" -> ")), name.span()));
+        }
+
+        if let Some(&file) = self.loaded.get(&canonical) {
+            return Ok(file);
+        }
+
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Ok(self.load(path, text, false)),
+            Err(e)   => Err(Error::new(format!("cannot read `{}`: {}", path.display(), e), name.span())),
+        }
+    }
+
+    fn read_binary(&self, from: &Path, bin: &Incbin<'_>) -> Result<Vec<u8>, Error> {
+        let name = &bin.path;
+        let path = match self.resolve(from, name.inner) {
+            Some(path) => path,
+            None       => return Err(Error::new(format!("cannot find `{}` to embed", name.inner), name.span())),
+        };
+
+        std::fs::read(&path)
+            .map_err(|e| Error::new(format!("cannot read `{}`: {}", path.display(), e), name.span()))
+    }
+
+    fn include_library(&mut self, name: &str) -> Option<usize> {
+        let &(_, text) = LIBRARIES.iter().find(|(lib, _)| *lib == name)?;
+        // Libraries don't include other files, so they can't be part of a cycle.
+        let path = PathBuf::from(format!("<{}>", name));
+        if let Some(&file) = self.loaded.get(&path) {
+            return Some(file);
+        }
+        Some(self.load(path, text.to_string(), true))
+    }
+
+    /// Looks for an included or embedded file next to the file that includes it and then in the include
+    /// directories.
+    fn resolve(&self, from: &Path, name: &str) -> Option<PathBuf> {
+        let dir = from.parent().unwrap_or_else(|| Path::new(""));
+        std::iter::once(dir)
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+}
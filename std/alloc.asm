@@ -0,0 +1,12 @@
+; Alocação de memória a partir de `heap`: alloc.
+
+; alloc(n) -> ptr: reserva n células e devolve o endereço da primeira.
+alloc:
+    add 'sp &2 '.arg
+    cpy *'.arg '.n
+    cpy 'heap *'.arg
+    add 'heap '.n 'heap
+    ret
+
+.arg: 0
+.n: 0
@@ -0,0 +1,104 @@
+; Entrada e saída: print_str, print_len e print_int.
+
+; print_str(str): imprime a string terminada em 0 no endereço str.
+print_str:
+    add 'sp &2 '.ptr        ; .ptr aponta para o argumento
+    cpy *'.ptr '.ptr        ; .ptr = str
+.loop:
+    cpy *'.ptr '.chr
+    ceq '.chr &0 '.end
+    beq '.end &'.done
+    put '.chr
+    add '.ptr &1 '.ptr
+    jmp &'.loop
+.done:
+    ret
+
+.ptr: 0
+.chr: 0
+.end: 0
+
+; print_len(str, n): imprime os n caracteres a partir do endereço str. O tamanho
+; de uma string pode ser calculado com `sizeof`.
+print_len:
+    add 'sp &2 '.ptr        ; .ptr aponta para o último argumento
+    cpy *'.ptr '.n          ; .n = n
+    add '.ptr &1 '.ptr
+    cpy *'.ptr '.ptr        ; .ptr = str
+.loop:
+    cle &0 '.n '.more
+    beq '.more &'.put
+    ret
+.put:
+    put *'.ptr
+    add '.ptr &1 '.ptr
+    add '.n &-1 '.n
+    jmp &'.loop
+
+.ptr: 0
+.n: 0
+.more: 0
+
+; print_int(n): imprime n em decimal, com sinal.
+print_int:
+    add 'sp &2 '.n
+    cpy *'.n '.n            ; .n = n
+    cle '.n &0 '.neg
+    beq '.neg &'.negative
+    jmp &'.digits
+.negative:
+    put &'-'
+    mul '.n &-1 '.n
+.digits:
+    cpy &'.powers '.pow_ptr
+    cpy &0 '.started
+.next:
+    cpy *'.pow_ptr '.pow
+    ceq '.pow &0 '.end
+    beq '.end &'.done
+    mul '.pow &-1 '.neg_pow
+    cpy &0 '.digit
+.sub:                       ; Subtrai a potência de 10 enquanto couber.
+    cle '.n '.pow '.less
+    beq '.less &'.print
+    add '.n '.neg_pow '.n
+    add '.digit &1 '.digit
+    jmp &'.sub
+.print:                     ; Zeros à esquerda não são impressos, exceto em 0.
+    add '.started '.digit '.started
+    ceq '.pow &1 '.last
+    add '.started '.last '.show
+    beq '.show &'.put
+    jmp &'.step
+.put:
+    add '.digit &'0' '.digit
+    put '.digit
+.step:
+    add '.pow_ptr &1 '.pow_ptr
+    jmp &'.next
+.done:
+    ret
+
+.n: 0
+.neg: 0
+.pow_ptr: 0
+.pow: 0
+.neg_pow: 0
+.digit: 0
+.less: 0
+.started: 0
+.last: 0
+.show: 0
+.end: 0
+.powers:
+    1000000000
+    100000000
+    10000000
+    1000000
+    100000
+    10000
+    1000
+    100
+    10
+    1
+    0
@@ -0,0 +1,79 @@
+; Aritmética: divmod.
+
+; divmod(a, b) -> (quociente, resto): divisão inteira que arredonda para zero,
+; então o resto tem o sinal de a. Divisão por zero resulta em quociente 0 e
+; resto a.
+divmod:
+    add 'sp &2 '.arg_b
+    cpy *'.arg_b '.b
+    add '.arg_b &1 '.arg_a
+    cpy *'.arg_a '.a
+    cpy &0 '.q
+    ceq '.b &0 '.t
+    beq '.t &'.store
+
+    cpy &0 '.q_neg
+    cpy &0 '.r_neg
+    cle '.a &0 '.t
+    beq '.t &'.a_neg
+    jmp &'.b_sign
+.a_neg:
+    mul '.a &-1 '.a
+    cpy &1 '.q_neg
+    cpy &1 '.r_neg
+.b_sign:
+    cle '.b &0 '.t
+    beq '.t &'.b_neg
+    jmp &'.outer
+.b_neg:
+    mul '.b &-1 '.b
+    ceq '.q_neg &0 '.q_neg
+
+.outer:                     ; Subtrai o maior b * 2^k que couber em a.
+    cle '.a '.b '.t
+    beq '.t &'.signs
+    cpy '.b '.d
+    cpy &1 '.m
+.inner:
+    add '.d '.d '.d2
+    cle &0 '.d2 '.t         ; Para antes de estourar.
+    beq '.t &'.fits
+    jmp &'.sub
+.fits:
+    cle '.a '.d2 '.t
+    beq '.t &'.sub
+    cpy '.d2 '.d
+    add '.m '.m '.m
+    jmp &'.inner
+.sub:
+    mul '.d &-1 '.d
+    add '.a '.d '.a
+    add '.q '.m '.q
+    jmp &'.outer
+
+.signs:
+    beq '.q_neg &'.neg_q
+    jmp &'.r_sign
+.neg_q:
+    mul '.q &-1 '.q
+.r_sign:
+    beq '.r_neg &'.neg_r
+    jmp &'.store
+.neg_r:
+    mul '.a &-1 '.a
+.store:
+    cpy '.q *'.arg_b
+    cpy '.a *'.arg_a
+    ret
+
+.arg_a: 0
+.arg_b: 0
+.a: 0
+.b: 0
+.q: 0
+.q_neg: 0
+.r_neg: 0
+.d: 0
+.d2: 0
+.m: 0
+.t: 0
@@ -0,0 +1,72 @@
+; Memória e strings: memcpy, memset e strlen.
+
+; memcpy(dst, src, n): copia n células de src para dst.
+memcpy:
+    add 'sp &2 '.arg
+    cpy *'.arg '.n
+    add '.arg &1 '.arg
+    cpy *'.arg '.src
+    add '.arg &1 '.arg
+    cpy *'.arg '.dst
+.loop:
+    cle &0 '.n '.more
+    beq '.more &'.copy
+    ret
+.copy:
+    cpy *'.src *'.dst
+    add '.src &1 '.src
+    add '.dst &1 '.dst
+    add '.n &-1 '.n
+    jmp &'.loop
+
+.arg: 0
+.dst: 0
+.src: 0
+.n: 0
+.more: 0
+
+; memset(dst, value, n): escreve value em n células a partir de dst.
+memset:
+    add 'sp &2 '.arg
+    cpy *'.arg '.n
+    add '.arg &1 '.arg
+    cpy *'.arg '.value
+    add '.arg &1 '.arg
+    cpy *'.arg '.dst
+.loop:
+    cle &0 '.n '.more
+    beq '.more &'.set
+    ret
+.set:
+    cpy '.value *'.dst
+    add '.dst &1 '.dst
+    add '.n &-1 '.n
+    jmp &'.loop
+
+.arg: 0
+.dst: 0
+.value: 0
+.n: 0
+.more: 0
+
+; strlen(str) -> len: o tamanho da string terminada em 0 no endereço str.
+strlen:
+    add 'sp &2 '.arg
+    cpy *'.arg '.ptr
+    cpy &0 '.len
+.loop:
+    cpy *'.ptr '.chr
+    ceq '.chr &0 '.end
+    beq '.end &'.done
+    add '.len &1 '.len
+    add '.ptr &1 '.ptr
+    jmp &'.loop
+.done:
+    cpy '.len *'.arg
+    ret
+
+.arg: 0
+.ptr: 0
+.chr: 0
+.len: 0
+.end: 0